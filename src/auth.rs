@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::{fs, io, path::Path};
+use serde::Deserialize;
+
+/// Who may send chunks to `listen`, nothing is required when empty
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    /// Shared token every client has to present
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Client names allowed to connect
+    #[serde(default)]
+    pub clients: HashSet<String>,
+}
+
+/// Credentials presented by a client in the first message of a connection
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    pub token: Option<String>,
+    pub client: Option<String>,
+}
+
+/// Constant time comparison
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Auth {
+    /// Read a JSON auth file: `{"token": "...", "clients": ["..."]}`, both optional
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Auth> {
+        let file = fs::File::open(path)?;
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.token.is_some() || !self.clients.is_empty()
+    }
+
+    /// Check the credentials of a client
+    pub fn check(&self, credentials: &Credentials) -> Result<(), &'static str> {
+        if let Some(token) = &self.token {
+            match &credentials.token {
+                Some(given) if same_token(token, given) => {},
+                Some(_) => return Err("Invalid token"),
+                None => return Err("Missing token"),
+            }
+        }
+        if !self.clients.is_empty() {
+            match &credentials.client {
+                Some(client) if self.clients.contains(client) => {},
+                Some(_) => return Err("Client not allowed"),
+                None => return Err("Missing client name"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(token: Option<&str>, clients: &[&str]) -> Auth {
+        Auth {
+            token: token.map(str::to_string),
+            clients: clients.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    fn credentials(token: Option<&str>, client: Option<&str>) -> Credentials {
+        Credentials {
+            token: token.map(str::to_string),
+            client: client.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn nothing_required() {
+        let auth = Auth::default();
+        assert!(!auth.is_required());
+        assert_eq!(auth.check(&credentials(None, None)), Ok(()));
+        assert_eq!(auth.check(&credentials(Some("any"), Some("anyone"))), Ok(()));
+    }
+
+    #[test]
+    fn token() {
+        let auth = auth(Some("secret"), &[]);
+        assert!(auth.is_required());
+        assert_eq!(auth.check(&credentials(Some("secret"), None)), Ok(()));
+        assert_eq!(auth.check(&credentials(Some("secreT"), None)), Err("Invalid token"));
+        assert_eq!(auth.check(&credentials(Some("secret2"), None)), Err("Invalid token"));
+        assert_eq!(auth.check(&credentials(Some(""), None)), Err("Invalid token"));
+        assert_eq!(auth.check(&credentials(None, Some("alice"))), Err("Missing token"));
+    }
+
+    #[test]
+    fn clients() {
+        let auth = auth(None, &["alice", "bob"]);
+        assert!(auth.is_required());
+        assert_eq!(auth.check(&credentials(None, Some("bob"))), Ok(()));
+        assert_eq!(auth.check(&credentials(None, Some("mallory"))), Err("Client not allowed"));
+        assert_eq!(auth.check(&credentials(Some("secret"), None)), Err("Missing client name"));
+    }
+
+    #[test]
+    fn token_and_clients() {
+        let auth = auth(Some("secret"), &["alice"]);
+        assert_eq!(auth.check(&credentials(Some("secret"), Some("alice"))), Ok(()));
+        assert_eq!(auth.check(&credentials(Some("wrong"), Some("alice"))), Err("Invalid token"));
+        assert_eq!(auth.check(&credentials(Some("secret"), Some("bob"))), Err("Client not allowed"));
+        assert_eq!(auth.check(&credentials(Some("secret"), None)), Err("Missing client name"));
+    }
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        fs::write(&path, r#"{"token": "secret", "clients": ["alice"]}"#).unwrap();
+        let auth = Auth::load(&path).unwrap();
+        assert_eq!(auth.token.as_deref(), Some("secret"));
+        assert!(auth.clients.contains("alice"));
+        fs::write(&path, r#"{"tokens": "secret"}"#).unwrap();
+        assert_eq!(Auth::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
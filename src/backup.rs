@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashSet};
+use std::{fs, io, io::{BufReader, BufWriter, Read, Write}};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::journal;
+use crate::region::{region_of, Region, RegionChunk};
+
+const BACKUP_DIR: &str = "dump-to-map-backups";
+const BACKUP_EXTENSION: &str = "bak";
+const UNDONE_EXTENSION: &str = "undone";
+const JOURNAL_EXTENSION: &str = "journal";
+const MAGIC: &[u8; 4] = b"D2MB";
+const VERSION: u8 = 1;
+
+/// Original content of the chunks overwritten by an import session, so the session can be undone.
+///
+/// The sidecar file is a `D2MB` magic and a version byte followed by one record per chunk:
+/// chunk x and z (`i32`), a presence byte and, when the chunk existed, its region timestamp (`u32`),
+/// compression byte, compressed length (`u32`) and compressed NBT as stored in the region file.
+/// Only the first snapshot of a chunk is kept, it is the content from before the session.
+/// The journal lines written by the session are copied to `<session>.journal`, undo removes them from the journal.
+pub struct Backup {
+    path: PathBuf,
+    inner: Mutex<BackupFile>,
+}
+
+struct BackupFile {
+    file: BufWriter<fs::File>,
+    seen: HashSet<(i32, i32)>,
+}
+
+impl Backup {
+    /// Start the backup of a new session in `<world>/dump-to-map-backups/<session>.bak`
+    pub fn create<P: AsRef<Path>>(world: P) -> io::Result<Backup> {
+        let dir = world.as_ref().join(BACKUP_DIR);
+        fs::create_dir_all(&dir)?;
+        let session = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let path = dir.join(format!("{}.{}", session, BACKUP_EXTENSION));
+        let mut file = BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).open(&path)?);
+        file.write_all(MAGIC)?;
+        file.write_u8(VERSION)?;
+        file.flush()?;
+        info!("Backing up overwritten chunks to {}", path.display());
+        Ok(Backup {
+            path,
+            inner: Mutex::new(BackupFile {
+                file,
+                seen: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Save the content of a chunk before it is overwritten, `None` for a chunk which did not exist
+    pub fn snapshot(&self, chunk_x: i32, chunk_z: i32, chunk: Option<&RegionChunk>, timestamp: u32) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.seen.insert((chunk_x, chunk_z)) {
+            return Ok(());
+        }
+        let file = &mut inner.file;
+        file.write_i32::<BigEndian>(chunk_x)?;
+        file.write_i32::<BigEndian>(chunk_z)?;
+        match chunk {
+            Some(chunk) => {
+                file.write_u8(1)?;
+                file.write_u32::<BigEndian>(timestamp)?;
+                file.write_u8(chunk.compression)?;
+                file.write_u32::<BigEndian>(chunk.data.len() as u32)?;
+                file.write_all(&chunk.data)?;
+            },
+            None => file.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    /// Where the session copies its journal lines, see `Journal::with_session`
+    pub fn journal_path(&self) -> PathBuf {
+        self.path.with_extension(JOURNAL_EXTENSION)
+    }
+
+    /// Make sure the snapshots are on disk, must be called before writing the chunks they back up
+    pub fn flush(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.file.flush()?;
+        inner.file.get_ref().sync_data()
+    }
+
+    /// Close the session, the backup is removed if no chunk was touched
+    pub fn finish(self) -> io::Result<()> {
+        let mut inner = self.inner.into_inner().unwrap();
+        inner.file.flush()?;
+        if inner.seen.is_empty() {
+            drop(inner);
+            fs::remove_file(&self.path)?;
+            // Nothing to undo, the journal lines stay
+            remove_if_exists(&self.path.with_extension(JOURNAL_EXTENSION))?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+struct Snapshot {
+    chunk_x: i32,
+    chunk_z: i32,
+    chunk: Option<RegionChunk>,
+    timestamp: u32,
+}
+
+fn read_snapshots<P: AsRef<Path>>(path: P) -> io::Result<Vec<Snapshot>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || reader.read_u8()? != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a dump-to-map backup"));
+    }
+    let mut snapshots = vec![];
+    loop {
+        let snapshot = (|| -> io::Result<Snapshot> {
+            let chunk_x = reader.read_i32::<BigEndian>()?;
+            let chunk_z = reader.read_i32::<BigEndian>()?;
+            let (chunk, timestamp) = match reader.read_u8()? {
+                0 => (None, 0),
+                _ => {
+                    let timestamp = reader.read_u32::<BigEndian>()?;
+                    let compression = reader.read_u8()?;
+                    let len = reader.read_u32::<BigEndian>()?;
+                    // The length is not trusted to allocate, a damaged file could claim gigabytes
+                    let mut data = vec![];
+                    if reader.by_ref().take(len as u64).read_to_end(&mut data)? < len as usize {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    (Some(RegionChunk { compression, data }), timestamp)
+                },
+            };
+            Ok(Snapshot { chunk_x, chunk_z, chunk, timestamp })
+        })();
+        match snapshot {
+            Ok(snapshot) => snapshots.push(snapshot),
+            // End of the file, or a record cut by an interrupted session which was never applied
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Backups of a world which were not undone yet, oldest first
+pub fn sessions<P: AsRef<Path>>(world: P) -> io::Result<Vec<PathBuf>> {
+    let dir = world.as_ref().join(BACKUP_DIR);
+    let mut sessions: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some(BACKUP_EXTENSION))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    sessions.sort_by_key(|path| path.file_stem().and_then(|e| e.to_str()).and_then(|e| e.parse::<u128>().ok()).unwrap_or(0));
+    Ok(sessions)
+}
+
+/// Restore the chunks saved by a session (the latest one by default) and remove its dumps from the journal
+/// so they can be imported again, returning the number of restored chunks
+pub fn undo<P: AsRef<Path>>(world: P, session: Option<&str>) -> io::Result<usize> {
+    let world = world.as_ref();
+    let mut sessions = sessions(world)?;
+    let path = sessions.pop().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No import to undo"))?;
+    if let Some(session) = session {
+        // The chunks of an older session may have been overwritten again by the newer ones
+        let requested = world.join(BACKUP_DIR).join(format!("{}.{}", session, BACKUP_EXTENSION));
+        if requested != path {
+            let message = match sessions.contains(&requested) {
+                true => format!("Session {} is not the latest one, undo {} first", session, path.file_stem().and_then(|e| e.to_str()).unwrap_or_default()),
+                false => format!("No session {} to undo", session),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+    }
+    let mut regions: BTreeMap<(i32, i32), Vec<Snapshot>> = BTreeMap::new();
+    for snapshot in read_snapshots(&path)? {
+        regions.entry(region_of(snapshot.chunk_x, snapshot.chunk_z)).or_default().push(snapshot);
+    }
+    let mut restored = 0;
+    for ((x, z), snapshots) in regions {
+        let mut region = Region::open(world, x, z)?;
+        for snapshot in snapshots {
+            region.set_raw_chunk(snapshot.chunk_x, snapshot.chunk_z, snapshot.chunk, snapshot.timestamp);
+            restored += 1;
+        }
+        if region.is_empty() {
+            // The region was created by the import
+            if region.exists() {
+                fs::remove_file(region.path())?;
+            }
+        } else {
+            region.save()?;
+        }
+    }
+    let journal_path = path.with_extension(JOURNAL_EXTENSION);
+    let forgotten = journal::forget(world, &journal_path)?;
+    fs::rename(&path, path.with_extension(UNDONE_EXTENSION))?;
+    remove_if_exists(&journal_path)?;
+    info!("Undone {}, {} chunks restored, {} dumps removed from the journal", path.display(), restored, forgotten);
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.bak");
+        let mut file = MAGIC.to_vec();
+        file.push(VERSION);
+        // A chunk which did not exist
+        file.extend_from_slice(&[0, 0, 0, 1, 0xff, 0xff, 0xff, 0xfe, 0]);
+        // A chunk claiming 4 GiB of data, cut after 3 bytes
+        file.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 3, 1, 0x5e, 0xb9, 0x50, 0x00, 2, 0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+        fs::write(&path, file).unwrap();
+        let snapshots = read_snapshots(&path).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!((snapshots[0].chunk_x, snapshots[0].chunk_z), (1, -2));
+        assert!(snapshots[0].chunk.is_none());
+    }
+}
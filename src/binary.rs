@@ -0,0 +1,243 @@
+//! Binary chunk dumps, much smaller and faster to read than the JSON ones.
+//!
+//! Every field is big-endian:
+//!
+//! | Field          | Type                                                 | Present              |
+//! |----------------|------------------------------------------------------|----------------------|
+//! | magic          | `D2MC`                                               | always               |
+//! | version        | `u8` (1)                                             | always               |
+//! | x, z           | `i32`, `i32`                                         | always               |
+//! | flags          | `u8`                                                 | always               |
+//! | bit map        | `i32`                                                | always               |
+//! | timestamp      | `u64` (ms since the epoch)                           | flags & `TIMESTAMP`  |
+//! | biomes         | `u32` count, `i32` each                              | flags & `BIOMES`     |
+//! | heightmaps     | `u32` length, NBT compound                           | flags & `HEIGHTMAPS` |
+//! | chunk data     | `u32` length, packet bytes                           | always               |
+//! | block entities | `u32` count, then `u32` length and NBT compound each | always               |
+//!
+//! Flags are `GROUND_UP` (1), `BIOMES` (2), `HEIGHTMAPS` (4) and `TIMESTAMP` (8).
+//! NBT is uncompressed and the chunk data is the data field of the Chunk Data packet, as is.
+
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::models::{ChunkData, PacketChunk, ReadArrayExt};
+use crate::nbt_json::{read_nbt, write_nbt};
+
+pub const MAGIC: &[u8; 4] = b"D2MC";
+pub const EXTENSION: &str = "d2m";
+const VERSION: u8 = 1;
+
+const GROUND_UP: u8 = 1;
+const BIOMES: u8 = 2;
+const HEIGHTMAPS: u8 = 4;
+const TIMESTAMP: u8 = 8;
+
+/// Biomes of a 1.15 chunk, 4x4x4 cells
+const MAX_BIOMES: usize = 1024;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Binary dumps are recognized by their magic, whatever their file name
+pub fn is_binary(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Chunk coordinates of a binary dump, read from its header only
+pub fn read_position(data: &[u8]) -> Option<(i32, i32)> {
+    if !is_binary(data) {
+        return None;
+    }
+    let mut header = data.get(MAGIC.len() + 1..)?;
+    let x = header.read_i32::<BigEndian>().ok()?;
+    let z = header.read_i32::<BigEndian>().ok()?;
+    Some((x, z))
+}
+
+/// Length prefixed bytes, read without trusting the length for the allocation
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>()? as u64;
+    let mut bytes = Vec::new();
+    r.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated binary dump"));
+    }
+    Ok(bytes)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+/// Write a dump in the binary format
+pub fn write<W: Write>(w: &mut W, chunk: &PacketChunk) -> io::Result<()> {
+    let mut flags = 0;
+    if chunk.ground_up {
+        flags |= GROUND_UP;
+    }
+    if chunk.biomes.is_some() {
+        flags |= BIOMES;
+    }
+    if chunk.heightmaps.is_some() {
+        flags |= HEIGHTMAPS;
+    }
+    if chunk.timestamp.is_some() {
+        flags |= TIMESTAMP;
+    }
+    w.write_all(MAGIC)?;
+    w.write_u8(VERSION)?;
+    w.write_i32::<BigEndian>(chunk.x)?;
+    w.write_i32::<BigEndian>(chunk.z)?;
+    w.write_u8(flags)?;
+    w.write_i32::<BigEndian>(chunk.bit_map)?;
+    if let Some(timestamp) = chunk.timestamp {
+        w.write_u64::<BigEndian>(timestamp)?;
+    }
+    if let Some(biomes) = &chunk.biomes {
+        w.write_u32::<BigEndian>(biomes.len() as u32)?;
+        for biome in biomes {
+            w.write_i32::<BigEndian>(*biome)?;
+        }
+    }
+    if let Some(heightmaps) = &chunk.heightmaps {
+        let heightmaps = serde_json::to_value(heightmaps).map_err(|e| invalid(e.to_string()))?;
+        let mut nbt = Vec::new();
+        write_nbt(&mut nbt, &heightmaps)?;
+        write_bytes(w, &nbt)?;
+    }
+    write_bytes(w, chunk.chunk_data.bytes())?;
+    let block_entities = chunk.block_entities.as_array().map(|e| &e[..]).unwrap_or_default();
+    w.write_u32::<BigEndian>(block_entities.len() as u32)?;
+    for block_entity in block_entities {
+        let mut nbt = Vec::new();
+        write_nbt(&mut nbt, block_entity)?;
+        write_bytes(w, &nbt)?;
+    }
+    Ok(())
+}
+
+/// Read a binary dump, the magic included
+pub fn read<R: Read>(r: &mut R) -> io::Result<PacketChunk> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("Not a binary dump".to_string()));
+    }
+    let version = r.read_u8()?;
+    if version != VERSION {
+        return Err(invalid(format!("Unsupported binary dump version {}", version)));
+    }
+    let x = r.read_i32::<BigEndian>()?;
+    let z = r.read_i32::<BigEndian>()?;
+    let flags = r.read_u8()?;
+    let bit_map = r.read_i32::<BigEndian>()?;
+    let timestamp = match flags & TIMESTAMP {
+        0 => None,
+        _ => Some(r.read_u64::<BigEndian>()?),
+    };
+    let biomes = match flags & BIOMES {
+        0 => None,
+        _ => {
+            let len = r.read_u32::<BigEndian>()? as usize;
+            if len > MAX_BIOMES {
+                return Err(invalid(format!("Invalid biome count {}", len)));
+            }
+            Some(r.read_i32_array(len)?)
+        },
+    };
+    let heightmaps = match flags & HEIGHTMAPS {
+        0 => None,
+        _ => {
+            let heightmaps = read_nbt(&mut &read_bytes(r)?[..])?;
+            Some(serde_json::from_value(heightmaps).map_err(|e| invalid(e.to_string()))?)
+        },
+    };
+    let chunk_data = ChunkData::new(read_bytes(r)?);
+    let count = r.read_u32::<BigEndian>()?;
+    let mut block_entities = vec![];
+    for _ in 0..count {
+        block_entities.push(read_nbt(&mut &read_bytes(r)?[..])?);
+    }
+    Ok(PacketChunk {
+        x,
+        z,
+        ground_up: flags & GROUND_UP != 0,
+        bit_map,
+        heightmaps,
+        biomes,
+        chunk_data,
+        block_entities: serde_json::Value::Array(block_entities),
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk() -> PacketChunk {
+        PacketChunk {
+            x: 3,
+            z: -7,
+            ground_up: true,
+            bit_map: 0b101,
+            heightmaps: Some(serde_json::from_value(json!({
+                "type": "compound",
+                "name": "",
+                "value": {"MOTION_BLOCKING": {"type": "longArray", "value": [[0, 1], [-1, -2]]}},
+            })).unwrap()),
+            biomes: Some((0..1024).collect()),
+            chunk_data: ChunkData::new(vec![1, 2, 3, 4]),
+            block_entities: json!([{
+                "type": "compound",
+                "name": "",
+                "value": {"id": {"type": "string", "value": "minecraft:chest"}, "x": {"type": "int", "value": 12}},
+            }]),
+            timestamp: Some(1589472000000),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = chunk();
+        let mut data = vec![];
+        write(&mut data, &chunk).unwrap();
+        assert!(is_binary(&data));
+        assert_eq!(read_position(&data), Some((3, -7)));
+        let decoded = read(&mut &data[..]).unwrap();
+        assert_eq!((decoded.x, decoded.z, decoded.ground_up, decoded.bit_map), (3, -7, true, 0b101));
+        assert_eq!(decoded.biomes, chunk.biomes);
+        assert_eq!(decoded.timestamp, chunk.timestamp);
+        assert_eq!(decoded.chunk_data.bytes(), &[1, 2, 3, 4]);
+        assert_eq!(serde_json::to_value(&decoded.heightmaps).unwrap(), serde_json::to_value(&chunk.heightmaps).unwrap());
+        assert_eq!(decoded.block_entities, chunk.block_entities);
+    }
+
+    #[test]
+    fn round_trip_without_optional_fields() {
+        let mut chunk = chunk();
+        chunk.ground_up = false;
+        chunk.heightmaps = None;
+        chunk.biomes = None;
+        chunk.timestamp = None;
+        chunk.block_entities = json!([]);
+        let mut data = vec![];
+        write(&mut data, &chunk).unwrap();
+        let decoded = read(&mut &data[..]).unwrap();
+        assert!(!decoded.ground_up);
+        assert!(decoded.heightmaps.is_none() && decoded.biomes.is_none() && decoded.timestamp.is_none());
+        assert_eq!(decoded.block_entities, json!([]));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = vec![];
+        write(&mut data, &chunk()).unwrap();
+        assert!(read(&mut &data[..data.len() - 1]).is_err());
+        assert!(read(&mut &b"D2MX"[..]).is_err());
+    }
+}
@@ -0,0 +1,913 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::{fs, io, thread};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use nbt::CompoundTag;
+
+use crate::region::{region_of, Region};
+use crate::models::PacketChunk;
+use crate::progress::Progress;
+use crate::journal::{content_hash, Capture, Journal};
+use crate::conflict::ConflictPolicy;
+use crate::backup::Backup;
+use crate::input::{dump_xz, for_each_entry, for_each_line, is_archive, is_ndjson, open_lines, read_dump, RawDump};
+
+/// Dumps waiting in front of each shard of a streamed import
+const STREAM_BACKLOG: usize = 64;
+/// Regions a shard keeps in memory
+const SHARD_REGIONS: usize = 16;
+
+/// All the dumps targeting a single region file
+#[derive(Debug)]
+pub struct RegionJob {
+    pub x: i32,
+    pub z: i32,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Group the dumps of a directory (plain, `.gz` or `.zst` JSON) by the region file their chunk belongs to.
+/// Archives and NDJSON files are left out, see `get_streams`
+pub fn get_chunks_fmap<T: AsRef<Path>>(dir: T) -> io::Result<Vec<RegionJob>> {
+    let dir = dir.as_ref();
+    let paths: Vec<PathBuf> = match dir.is_file() {
+        true => vec![dir.to_path_buf()],
+        false => fs::read_dir(dir)?.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+    };
+    let mut ret: BTreeMap<(i32, i32), Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        if is_archive(&path) || is_ndjson(&path) {
+            continue;
+        }
+        if let Some((x, z)) = dump_xz(&path) {
+            ret.entry(region_of(x, z)).or_default().push(path);
+        }
+    }
+    Ok(ret.into_iter().map(|((x, z), paths)| RegionJob { x, z, paths }).collect())
+}
+
+/// Input streamed dump by dump instead of being grouped by region up front
+#[derive(Debug)]
+pub enum StreamInput {
+    /// `.tar`, `.tar.gz` or `.zip` archive of dumps
+    Archive(PathBuf),
+    /// Newline-delimited JSON file, one dump per line
+    Lines(PathBuf),
+    /// Newline-delimited JSON read from the standard input
+    Stdin,
+}
+
+impl StreamInput {
+    pub fn path(&self) -> PathBuf {
+        match self {
+            StreamInput::Archive(path) | StreamInput::Lines(path) => path.clone(),
+            StreamInput::Stdin => PathBuf::from("stdin"),
+        }
+    }
+
+    /// Read the dumps of the input one by one, entries which can't be read are passed to `f` as errors
+    pub fn for_each<F: FnMut(Result<RawDump, (PathBuf, io::Error)>)>(&self, mut f: F) -> io::Result<()> {
+        match self {
+            StreamInput::Archive(path) => for_each_entry(path, f),
+            StreamInput::Lines(path) => for_each_line(open_lines(path)?, path, |raw| f(Ok(raw))),
+            StreamInput::Stdin => {
+                let stdin = io::stdin();
+                for_each_line(stdin.lock(), Path::new("stdin"), |raw| f(Ok(raw)))
+            },
+        }
+    }
+}
+
+/// Archives and NDJSON files of a directory, or the patch itself when it is one of them
+pub fn get_streams<T: AsRef<Path>>(dir: T) -> io::Result<Vec<StreamInput>> {
+    let dir = dir.as_ref();
+    if dir == Path::new("-") {
+        return Ok(vec![StreamInput::Stdin]);
+    }
+    let mut paths: Vec<PathBuf> = match dir.is_file() {
+        true => vec![dir.to_path_buf()],
+        false => fs::read_dir(dir)?.filter_map(|e| e.ok()).map(|e| e.path()).filter(|path| path.is_file()).collect(),
+    };
+    paths.sort();
+    Ok(paths.into_iter()
+        .filter_map(|path| match () {
+            _ if is_archive(&path) => Some(StreamInput::Archive(path)),
+            _ if is_ndjson(&path) => Some(StreamInput::Lines(path)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Region jobs shared by the workers, each job is popped by a single worker
+pub struct WorkQueue {
+    jobs: Mutex<VecDeque<RegionJob>>,
+}
+
+impl WorkQueue {
+    /// Biggest regions are scheduled first so the last jobs are the short ones
+    pub fn new(mut jobs: Vec<RegionJob>) -> Self {
+        jobs.sort_by_key(|job| Reverse(job.paths.len()));
+        Self {
+            jobs: Mutex::new(jobs.into_iter().collect()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    pub fn pop(&self) -> Option<RegionJob> {
+        self.jobs.lock().unwrap().pop_front()
+    }
+}
+
+/// Options of the `bulk` subcommand
+pub struct BulkOptions {
+    /// Number of worker threads
+    pub jobs: usize,
+    /// Skip the dumps recorded in the journal by a previous run
+    pub resume: bool,
+    /// Directory where unreadable dumps are moved
+    pub quarantine: Option<PathBuf>,
+    /// What to do with chunks already in the world
+    pub on_conflict: ConflictPolicy,
+    /// Which dump to keep when several dumps target the same chunk
+    pub pick: Pick,
+    /// Go through the whole import without writing anything
+    pub dry_run: bool,
+    /// Save the chunks overwritten by the import so it can be undone
+    pub backup: bool,
+}
+
+/// Which capture wins when several dumps target the same chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pick {
+    /// The most recent capture
+    Latest,
+    /// The capture with the most sections, the most recent one on ties
+    Complete,
+}
+
+pub const PICKS: [&str; 2] = ["latest", "complete"];
+
+impl Default for Pick {
+    fn default() -> Self {
+        Pick::Latest
+    }
+}
+
+impl std::str::FromStr for Pick {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(Pick::Latest),
+            "complete" => Ok(Pick::Complete),
+            _ => Err(format!("Unknown pick {}", s)),
+        }
+    }
+}
+
+impl Pick {
+    /// `--pick` key of a capture
+    fn key(&self, sections: u32, timestamp: u64) -> (u32, u64) {
+        match self {
+            Pick::Latest => (0, timestamp),
+            Pick::Complete => (sections, timestamp),
+        }
+    }
+}
+
+/// A chunk already decoded, e.g. rebuilt from a packet capture
+pub struct DecodedDump {
+    /// Where the chunk comes from, recorded in the journal and the failures
+    pub label: PathBuf,
+    pub hash: u64,
+    pub x: i32,
+    pub z: i32,
+    /// Number of sections sent for the chunk, for `--pick complete`
+    pub sections: u32,
+    /// Capture time, or any value growing with the capture order
+    pub timestamp: u64,
+    pub chunk: CompoundTag,
+}
+
+/// A dump waiting to be applied to its region
+pub enum Dump {
+    /// Content of a dump file, decoded by the worker
+    Raw(RawDump),
+    Decoded(DecodedDump),
+}
+
+impl Dump {
+    pub fn label(&self) -> &Path {
+        match self {
+            Dump::Raw(raw) => &raw.label,
+            Dump::Decoded(decoded) => &decoded.label,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureKind {
+    /// The dump file could not be read
+    Read,
+    /// The dump is not a valid `PacketChunk`
+    Parse,
+    /// The chunk data of the dump could not be decoded
+    Decode,
+    /// The chunk could not be encoded into the region
+    Save,
+    /// The region file holding the chunk could not be read or written
+    Region,
+}
+
+impl FailureKind {
+    /// Failures caused by the dump itself rather than by the world
+    pub fn is_bad_input(&self) -> bool {
+        match self {
+            FailureKind::Read | FailureKind::Parse | FailureKind::Decode => true,
+            FailureKind::Save | FailureKind::Region => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:?} error: {}", self.path.display(), self.kind, self.reason)
+    }
+}
+
+/// Failures of every worker, reported at the end of the import
+#[derive(Default)]
+pub struct FailureReport {
+    failures: Mutex<Vec<Failure>>,
+}
+
+impl FailureReport {
+    fn push(&self, failure: Failure) {
+        self.failures.lock().unwrap().push(failure);
+    }
+
+    /// Print every failure and move the bad dumps to the quarantine directory
+    fn finish(self, quarantine: Option<&Path>) -> Vec<Failure> {
+        let failures = self.failures.into_inner().unwrap();
+        for failure in failures.iter() {
+            eprintln!("{}", failure);
+        }
+        if let Some(quarantine) = quarantine {
+            if let Err(e) = fs::create_dir_all(quarantine) {
+                error!("Failed to create quarantine directory {}: {}", quarantine.display(), e);
+                return failures;
+            }
+            // Dumps read from an archive stay in their archive
+            for failure in failures.iter().filter(|failure| failure.kind.is_bad_input() && failure.path.is_file()) {
+                if let Err(e) = quarantine_file(&failure.path, quarantine) {
+                    error!("Failed to quarantine {}: {}", failure.path.display(), e);
+                }
+            }
+        }
+        failures
+    }
+}
+
+fn quarantine_file(path: &Path, quarantine: &Path) -> io::Result<()> {
+    let target = quarantine.join(path.file_name().unwrap_or_default());
+    if fs::rename(path, &target).is_err() {
+        // Quarantine on another file system
+        fs::copy(path, &target)?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// What an import did, or would do in dry run mode, to the world
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub created_regions: Vec<PathBuf>,
+    pub modified_regions: Vec<PathBuf>,
+    pub added_chunks: usize,
+    pub overwritten_chunks: usize,
+}
+
+impl Changes {
+    pub fn print(&self, dry_run: bool) {
+        let (create, modify, add, overwrite) = if dry_run {
+            ("Would create", "Would modify", "would add", "would overwrite")
+        } else {
+            ("Created", "Modified", "added", "overwritten")
+        };
+        eprintln!("{} {} region files", create, self.created_regions.len());
+        for path in self.created_regions.iter() {
+            eprintln!("  {}", path.display());
+        }
+        eprintln!("{} {} region files", modify, self.modified_regions.len());
+        for path in self.modified_regions.iter() {
+            eprintln!("  {}", path.display());
+        }
+        eprintln!("Chunks: {} {}, {} {}", self.added_chunks, add, self.overwritten_chunks, overwrite);
+    }
+}
+
+/// State shared by the workers of an import
+pub struct Import {
+    folder: PathBuf,
+    options: BulkOptions,
+    progress: Arc<Progress>,
+    journal: Journal,
+    report: FailureReport,
+    changes: Mutex<Changes>,
+    backup: Option<Backup>,
+    /// `--pick` state of the regions between their batches
+    picked: Mutex<HashMap<(i32, i32), RegionPicks>>,
+}
+
+impl Import {
+    /// Hand the `--pick` state of a region over to its next batch
+    fn carry(&self, x: i32, z: i32, picks: RegionPicks) {
+        if !picks.is_empty() {
+            self.picked.lock().unwrap().insert((x, z), picks);
+        }
+    }
+}
+
+/// Path, content hash and capture of a dump
+type JournalDump = (PathBuf, u64, Capture);
+
+/// What `--pick` knows about a chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Picked {
+    /// `--pick` key of the best capture seen so far
+    key: (u32, u64),
+    /// The chunk was in the world before the import
+    existed: bool,
+    /// A capture was written to the world by an earlier batch of the import
+    imported: bool,
+}
+
+/// `--pick` state of the chunks of a region
+type RegionPicks = HashMap<(i32, i32), Picked>;
+
+/// Dumps applied in memory to one region file, written once by `finish`
+struct RegionBatch {
+    region: Region,
+    import: Arc<Import>,
+    picks: RegionPicks,
+    /// Dump written to each chunk of the region in memory
+    applied: HashMap<(i32, i32), JournalDump>,
+    /// Chunks from before the import, loaded for the conflict policy
+    originals: HashMap<(i32, i32), Option<CompoundTag>>,
+    /// Superseded dumps, recorded in the journal with the applied ones
+    kept: Vec<JournalDump>,
+}
+
+impl RegionBatch {
+    pub fn new(region: Region, import: Arc<Import>) -> Self {
+        let picks = import.picked.lock().unwrap().remove(&(region.x, region.z)).unwrap_or_default();
+        Self {
+            region,
+            import,
+            picks,
+            applied: HashMap::new(),
+            originals: HashMap::new(),
+            kept: vec![],
+        }
+    }
+
+    fn fail(&self, path: PathBuf, kind: FailureKind, reason: String) {
+        self.import.progress.chunk_failed();
+        self.import.report.push(Failure { path, kind, reason });
+    }
+
+    /// Apply a dump to the region in memory
+    pub fn feed(&mut self, dump: Dump) {
+        let path = dump.label().to_path_buf();
+        if let Err((kind, reason)) = self.apply(dump) {
+            self.fail(path, kind, reason);
+        }
+    }
+
+    fn apply(&mut self, dump: Dump) -> Result<(), (FailureKind, String)> {
+        match dump {
+            Dump::Raw(raw) => {
+                let hash = content_hash(&raw.data);
+                if self.skip_imported(&raw.label, hash) {
+                    return Ok(());
+                }
+                let chunk = PacketChunk::parse(&raw.data).map_err(|e| (FailureKind::Parse, e.to_string()))?;
+                let capture = Capture {
+                    x: chunk.x,
+                    z: chunk.z,
+                    sections: chunk.bit_map.count_ones(),
+                    timestamp: chunk.timestamp.or(raw.mtime).unwrap_or(0),
+                };
+                if self.superseded(&raw.label, capture) {
+                    self.kept.push((raw.label, hash, capture));
+                    return Ok(());
+                }
+                let chunk = chunk.into_compound_tag().map_err(|e| (FailureKind::Decode, e.to_string()))?;
+                self.write((raw.label, hash, capture), chunk)
+            },
+            Dump::Decoded(decoded) => {
+                if self.skip_imported(&decoded.label, decoded.hash) {
+                    return Ok(());
+                }
+                let capture = Capture {
+                    x: decoded.x,
+                    z: decoded.z,
+                    sections: decoded.sections,
+                    timestamp: decoded.timestamp,
+                };
+                if self.superseded(&decoded.label, capture) {
+                    self.kept.push((decoded.label, decoded.hash, capture));
+                    return Ok(());
+                }
+                self.write((decoded.label, decoded.hash, capture), decoded.chunk)
+            },
+        }
+    }
+
+    fn key(&self, capture: Capture) -> (u32, u64) {
+        self.import.options.pick.key(capture.sections, capture.timestamp)
+    }
+
+    /// `--pick` state of a chunk, starting from the capture `key`
+    fn picked(&mut self, chunk_x: i32, chunk_z: i32, key: (u32, u64)) -> &mut Picked {
+        let region = &self.region;
+        self.picks.entry((chunk_x, chunk_z)).or_insert_with(|| Picked {
+            key,
+            existed: region.contains(chunk_x, chunk_z),
+            imported: false,
+        })
+    }
+
+    /// Whether a dump was imported by an earlier run, unless this run wrote a worse capture of its chunk
+    fn skip_imported(&mut self, label: &Path, hash: u64) -> bool {
+        let capture = match self.import.journal.get(label, hash) {
+            Some(capture) => capture,
+            None => return false,
+        };
+        let key = self.key(capture);
+        let written = self.applied.contains_key(&(capture.x, capture.z));
+        let picked = self.picked(capture.x, capture.z, key);
+        if picked.key < key && (written || picked.imported) {
+            debug!("{} imported again over a worse capture of {}:{}", label.display(), capture.x, capture.z);
+            return false;
+        }
+        picked.key = picked.key.max(key);
+        self.import.progress.chunk_already_imported();
+        true
+    }
+
+    /// Whether a better capture of the chunk was already seen, later dumps win ties
+    fn superseded(&self, label: &Path, capture: Capture) -> bool {
+        match self.picks.get(&(capture.x, capture.z)) {
+            Some(picked) if picked.key > self.key(capture) => {
+                debug!("{} superseded by a better capture of {}:{}", label.display(), capture.x, capture.z);
+                self.import.progress.chunk_superseded();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Chunk from before the import the conflict policy weighs a dump against
+    fn original(&mut self, chunk_x: i32, chunk_z: i32) -> Option<CompoundTag> {
+        if self.import.options.on_conflict == ConflictPolicy::Overwrite {
+            return None;
+        }
+        // Only written by an earlier batch
+        if let Some(Picked { existed: false, imported: true, .. }) = self.picks.get(&(chunk_x, chunk_z)) {
+            return None;
+        }
+        let region = &self.region;
+        self.originals.entry((chunk_x, chunk_z))
+            .or_insert_with(|| region.load_chunk(chunk_x, chunk_z).unwrap_or_else(|e| {
+                warn!("{}:{} Existing chunk is unreadable, overwriting it: {}", chunk_x, chunk_z, e);
+                None
+            }))
+            .clone()
+    }
+
+    fn write(&mut self, dump: JournalDump, chunk: CompoundTag) -> Result<(), (FailureKind, String)> {
+        let (chunk_x, chunk_z) = (dump.2.x, dump.2.z);
+        let key = self.key(dump.2);
+        // Whether the chunk existed is known before it is written
+        let imported = self.picked(chunk_x, chunk_z, key).imported;
+        let original = self.original(chunk_x, chunk_z);
+        let chunk = match self.import.options.on_conflict.resolve(original, chunk) {
+            Some(chunk) => chunk,
+            None => {
+                // Not recorded in the journal, another run may use another policy
+                debug!("{} kept out by --on-conflict", dump.0.display());
+                self.picked(chunk_x, chunk_z, key).key = key;
+                self.import.progress.chunk_kept();
+                return Ok(());
+            },
+        };
+        if let Some(backup) = &self.import.backup {
+            // Only the first snapshot of a chunk is kept, the one from before the import
+            let (existing, timestamp) = self.region.raw_chunk(chunk_x, chunk_z);
+            backup.snapshot(chunk_x, chunk_z, existing, timestamp).map_err(|e| (FailureKind::Save, format!("{}:{} Failed to backup: {}", chunk_x, chunk_z, e)))?;
+        }
+        self.region.set_chunk(chunk_x, chunk_z, &chunk).map_err(|e| (FailureKind::Save, format!("{}:{} {}", chunk_x, chunk_z, e)))?;
+        self.picked(chunk_x, chunk_z, key).key = key;
+        let progress = &self.import.progress;
+        if let Some(superseded) = self.applied.insert((chunk_x, chunk_z), dump) {
+            debug!("{} superseded by a better capture of {}:{}", superseded.0.display(), chunk_x, chunk_z);
+            self.kept.push(superseded);
+            progress.chunk_replaced();
+        } else if imported {
+            debug!("{}:{} Earlier import superseded by a better capture", chunk_x, chunk_z);
+            progress.chunk_replaced();
+        }
+        progress.chunk_done();
+        Ok(())
+    }
+
+    /// Write the region file and record its dumps in the journal
+    pub fn finish(mut self) {
+        let dry_run = self.import.options.dry_run;
+        if !self.applied.is_empty() {
+            let save = match dry_run {
+                true => Ok(()),
+                false => match &self.import.backup {
+                    Some(backup) => backup.flush(),
+                    None => Ok(()),
+                }.and_then(|_| self.region.save()),
+            };
+            if let Err(e) = save {
+                // Every chunk applied in memory is lost with the region
+                let reason = format!("r.{}.{}.mca {}", self.region.x, self.region.z, e);
+                self.import.progress.chunks_lost(self.applied.len());
+                for (xz, (path, _, _)) in self.applied.drain() {
+                    // The region on disk is as it was before the batch
+                    self.picks.remove(&xz);
+                    self.import.report.push(Failure { path, kind: FailureKind::Region, reason: reason.clone() });
+                }
+                self.import.carry(self.region.x, self.region.z, self.picks);
+                return;
+            }
+            // Chunks written by an earlier batch are already counted
+            let picks = &self.picks;
+            let new = |existed: bool| self.applied.keys().filter(|xz| picks.get(xz).map_or(false, |p| !p.imported && p.existed == existed)).count();
+            let (added, overwritten) = (new(false), new(true));
+            let mut changes = self.import.changes.lock().unwrap();
+            if self.region.exists() {
+                changes.modified_regions.push(self.region.path().to_path_buf());
+            } else {
+                changes.created_regions.push(self.region.path().to_path_buf());
+            }
+            changes.added_chunks += added;
+            changes.overwritten_chunks += overwritten;
+        }
+        for xz in self.applied.keys() {
+            if let Some(picked) = self.picks.get_mut(xz) {
+                picked.imported = true;
+            }
+        }
+        if !dry_run {
+            let handled: Vec<_> = self.applied.into_iter().map(|(_, dump)| dump).chain(self.kept).collect();
+            if let Err(e) = self.import.journal.record(&handled) {
+                error!("Failed to update the journal: {}", e);
+            }
+        }
+        self.import.carry(self.region.x, self.region.z, self.picks);
+    }
+}
+
+/// Worker importing whole region jobs, one region at a time
+fn spawn_worker(queue: Arc<WorkQueue>, import: Arc<Import>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Some(job) = queue.pop() {
+            let (x, z) = (job.x, job.z);
+            match Region::open(&import.folder, x, z) {
+                Ok(region) => {
+                    let mut batch = RegionBatch::new(region, import.clone());
+                    for path in job.paths {
+                        match read_dump(&path) {
+                            Ok(raw) => batch.feed(Dump::Raw(raw)),
+                            Err(e) => batch.fail(path, FailureKind::Read, e.to_string()),
+                        }
+                    }
+                    batch.finish();
+                    debug!("r.{}.{}.mca done, {} region remaning ...", x, z, queue.len());
+                },
+                Err(e) => {
+                    let reason = format!("r.{}.{}.mca {}", x, z, e);
+                    for path in job.paths {
+                        import.progress.chunk_failed();
+                        import.report.push(Failure { path, kind: FailureKind::Region, reason: reason.clone() });
+                    }
+                },
+            }
+        }
+    })
+}
+
+/// Worker importing the streamed dumps of its regions, the least recently fed one is written past `SHARD_REGIONS`
+fn spawn_shard(dumps: mpsc::Receiver<((i32, i32), Dump)>, import: Arc<Import>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // Batches with the number of the dump which last used them
+        let mut batches: HashMap<(i32, i32), (usize, Result<RegionBatch, String>)> = HashMap::new();
+        for (fed, ((x, z), dump)) in dumps.into_iter().enumerate() {
+            if !batches.contains_key(&(x, z)) && batches.len() >= SHARD_REGIONS {
+                let oldest = batches.iter().min_by_key(|(_, (used, _))| *used).map(|(xz, _)| *xz);
+                if let Some((_, Ok(batch))) = oldest.and_then(|xz| batches.remove(&xz)) {
+                    debug!("r.{}.{}.mca evicted", batch.region.x, batch.region.z);
+                    batch.finish();
+                }
+            }
+            let (used, batch) = batches.entry((x, z)).or_insert_with(|| {
+                (0, Region::open(&import.folder, x, z)
+                    .map(|region| RegionBatch::new(region, import.clone()))
+                    .map_err(|e| format!("r.{}.{}.mca {}", x, z, e)))
+            });
+            *used = fed;
+            match batch {
+                Ok(batch) => batch.feed(dump),
+                Err(reason) => {
+                    import.progress.chunk_failed();
+                    import.report.push(Failure { path: dump.label().to_path_buf(), kind: FailureKind::Region, reason: reason.clone() });
+                },
+            }
+        }
+        for (_, (_, batch)) in batches {
+            if let Ok(batch) = batch {
+                batch.finish();
+            }
+        }
+    })
+}
+
+/// Sends streamed dumps to `jobs` shards, each region always goes to the same shard
+/// so a region file never has two writers
+pub struct Dispatcher {
+    import: Arc<Import>,
+    senders: Vec<mpsc::SyncSender<((i32, i32), Dump)>>,
+    shards: Vec<thread::JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    pub fn new(import: &Arc<Import>) -> Self {
+        let (senders, shards): (Vec<_>, Vec<_>) = (0..import.options.jobs.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::sync_channel(STREAM_BACKLOG);
+                (tx, spawn_shard(rx, import.clone()))
+            })
+            .unzip();
+        Self {
+            import: import.clone(),
+            senders,
+            shards,
+        }
+    }
+
+    /// Queue the dump of chunk `chunk_x`:`chunk_z`
+    pub fn send(&self, chunk_x: i32, chunk_z: i32, dump: Dump) {
+        self.import.progress.add_total(1);
+        let (x, z) = region_of(chunk_x, chunk_z);
+        let shard = (x.wrapping_mul(31).wrapping_add(z) as usize) % self.senders.len();
+        // A shard only stops when its channel is closed
+        let _ = self.senders[shard].send(((x, z), dump));
+    }
+
+    /// A dump which failed before reaching its region
+    pub fn fail(&self, path: PathBuf, kind: FailureKind, reason: String) {
+        self.import.progress.add_total(1);
+        self.import.progress.chunk_failed();
+        self.import.report.push(Failure { path, kind, reason });
+    }
+
+    /// An input which could not be read to the end, its dumps read so far are still imported
+    pub fn fail_input(&self, path: PathBuf, reason: String) {
+        self.import.report.push(Failure { path, kind: FailureKind::Read, reason });
+    }
+
+    /// Wait for the shards to write their regions
+    pub fn finish(self) {
+        drop(self.senders);
+        for shard in self.shards {
+            let _ = shard.join();
+        }
+    }
+}
+
+fn stream_dumps(inputs: Vec<StreamInput>, dispatcher: &Dispatcher) {
+    for input in inputs {
+        let streamed = input.for_each(|entry| {
+            let raw = match entry {
+                Ok(raw) => raw,
+                Err((path, e)) => return dispatcher.fail(path, FailureKind::Read, e.to_string()),
+            };
+            match raw.position() {
+                Some((x, z)) => dispatcher.send(x, z, Dump::Raw(raw)),
+                // An archive entry which is not a dump
+                None if raw.named => {},
+                None => dispatcher.fail(raw.label, FailureKind::Parse, "No chunk position".to_string()),
+            }
+        });
+        if let Err(e) = streamed {
+            dispatcher.fail_input(input.path(), e.to_string());
+        }
+    }
+}
+
+/// Number of workers used when `--jobs` is not given
+pub fn default_jobs() -> usize {
+    num_cpus::get()
+}
+
+/// Run an import session on the world: open the journal and the backup, show the progress while `f`
+/// feeds the dumps (`total` is the number of dumps known up front) then print the changes and the failures
+pub fn session<F: FnOnce(&Arc<Import>)>(output: &str, options: BulkOptions, total: usize, f: F) -> io::Result<Vec<Failure>> {
+    let output = PathBuf::from(output);
+    let backup = match options.backup && !options.dry_run {
+        true => Some(Backup::create(&output)?),
+        false => None,
+    };
+    let mut journal = Journal::open(&output, options.resume, !options.dry_run)?;
+    if let Some(backup) = &backup {
+        journal = journal.with_session(backup.journal_path())?;
+    }
+    if journal.len() > 0 {
+        info!("Resuming, {} dumps already imported", journal.len());
+    }
+    let progress = Progress::new(total);
+    let import = Arc::new(Import {
+        folder: output,
+        options,
+        progress: progress.clone(),
+        journal,
+        report: FailureReport::default(),
+        changes: Mutex::new(Changes::default()),
+        backup,
+        picked: Mutex::new(HashMap::new()),
+    });
+    let display = progress.display();
+    f(&import);
+    progress.finish(display, import.options.dry_run);
+    let import = Arc::try_unwrap(import).unwrap_or_else(|_| unreachable!("Workers are done"));
+    let mut changes = import.changes.into_inner().unwrap();
+    // A region written by several batches is reported once
+    changes.created_regions.sort();
+    changes.created_regions.dedup();
+    changes.modified_regions.sort();
+    changes.modified_regions.dedup();
+    let created = changes.created_regions.clone();
+    changes.modified_regions.retain(|path| created.binary_search(path).is_err());
+    changes.print(import.options.dry_run);
+    if let Some(backup) = import.backup {
+        backup.finish()?;
+    }
+    // Nothing is moved around in dry run mode
+    let quarantine = match import.options.dry_run {
+        true => None,
+        false => import.options.quarantine.as_deref(),
+    };
+    Ok(import.report.finish(quarantine))
+}
+
+/// Import the dumps of `patch` into the world, returning the dumps which could not be imported
+pub fn run(output: &str, patch: &str, options: BulkOptions) -> std::io::Result<Vec<Failure>> {
+    let patch = PathBuf::from(patch);
+    let jobs_list = match patch == Path::new("-") {
+        true => vec![],
+        false => get_chunks_fmap(&patch)?,
+    };
+    let streams = get_streams(&patch)?;
+    let total = jobs_list.iter().map(|job| job.paths.len()).sum();
+    session(output, options, total, |import| {
+        let queue = Arc::new(WorkQueue::new(jobs_list));
+        let nbr_thread = import.options.jobs.min(queue.len()).max(1);
+        let join: Vec<_> = (0..nbr_thread)
+            .map(|_| spawn_worker(queue.clone(), import.clone()))
+            .collect();
+        let mut cptr = join.len();
+        for join in join.into_iter() {
+            let _ = join.join();
+            cptr -= 1;
+            debug!("{} worker remaning ...", cptr);
+        }
+        // Regions of the dump files are done, streamed inputs may target them again with the captures
+        // picked so far carried over
+        let dispatcher = Dispatcher::new(import);
+        stream_dumps(streams, &dispatcher);
+        dispatcher.finish();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(world: &Path, pick: Pick, on_conflict: ConflictPolicy) -> Arc<Import> {
+        Arc::new(Import {
+            folder: world.to_path_buf(),
+            options: BulkOptions {
+                jobs: 1,
+                resume: false,
+                quarantine: None,
+                on_conflict,
+                pick,
+                dry_run: false,
+                backup: false,
+            },
+            progress: Progress::new(0),
+            journal: Journal::open(world, false, true).unwrap(),
+            report: FailureReport::default(),
+            changes: Mutex::new(Changes::default()),
+            backup: None,
+            picked: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn dump(name: &str, chunk_x: i32, chunk_z: i32, sections: u32, timestamp: u64) -> Dump {
+        let mut chunk = CompoundTag::new();
+        chunk.insert_str("Source", name);
+        Dump::Decoded(DecodedDump {
+            label: PathBuf::from(name),
+            hash: timestamp,
+            x: chunk_x,
+            z: chunk_z,
+            sections,
+            timestamp,
+            chunk,
+        })
+    }
+
+    /// Apply `dumps` to region 0:0 in a batch of their own
+    fn batch(import: &Arc<Import>, dumps: Vec<Dump>) {
+        let mut batch = RegionBatch::new(Region::open(&import.folder, 0, 0).unwrap(), import.clone());
+        for dump in dumps {
+            batch.feed(dump);
+        }
+        batch.finish();
+    }
+
+    fn source(world: &Path, chunk_x: i32, chunk_z: i32) -> Option<String> {
+        let chunk = Region::open(world, 0, 0).unwrap().load_chunk(chunk_x, chunk_z).unwrap();
+        chunk.map(|e| e.get_str("Source").unwrap().to_string())
+    }
+
+    #[test]
+    fn latest_capture_wins_across_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let import = import(world, Pick::Latest, ConflictPolicy::Overwrite);
+        batch(&import, vec![dump("a", 1, 1, 3, 10), dump("b", 1, 1, 3, 5)]);
+        assert_eq!(source(world, 1, 1).as_deref(), Some("a"));
+        // The region is opened again, the older capture still loses
+        batch(&import, vec![dump("c", 1, 1, 16, 7)]);
+        assert_eq!(source(world, 1, 1).as_deref(), Some("a"));
+        batch(&import, vec![dump("d", 1, 1, 1, 20)]);
+        assert_eq!(source(world, 1, 1).as_deref(), Some("d"));
+        let changes = import.changes.lock().unwrap();
+        assert_eq!((changes.added_chunks, changes.overwritten_chunks), (1, 0));
+    }
+
+    #[test]
+    fn complete_capture_wins_across_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let import = import(world, Pick::Complete, ConflictPolicy::Overwrite);
+        batch(&import, vec![dump("a", 1, 1, 5, 10)]);
+        batch(&import, vec![dump("b", 1, 1, 3, 20)]);
+        assert_eq!(source(world, 1, 1).as_deref(), Some("a"));
+        batch(&import, vec![dump("c", 1, 1, 5, 1), dump("d", 1, 1, 8, 2)]);
+        assert_eq!(source(world, 1, 1).as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn conflict_policy_weighs_the_chunk_from_before_the_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let mut region = Region::open(world, 0, 0).unwrap();
+        let mut existing = CompoundTag::new();
+        existing.insert_str("Source", "world");
+        region.set_chunk(2, 2, &existing).unwrap();
+        region.save().unwrap();
+
+        let import = import(world, Pick::Latest, ConflictPolicy::Skip);
+        batch(&import, vec![dump("a", 2, 2, 1, 10), dump("b", 3, 3, 1, 10)]);
+        assert_eq!(source(world, 2, 2).as_deref(), Some("world"));
+        assert_eq!(source(world, 3, 3).as_deref(), Some("b"));
+        // The chunk written by the first batch was not in the world, a better capture replaces it
+        batch(&import, vec![dump("c", 2, 2, 1, 20), dump("d", 3, 3, 1, 20)]);
+        assert_eq!(source(world, 2, 2).as_deref(), Some("world"));
+        assert_eq!(source(world, 3, 3).as_deref(), Some("d"));
+        let changes = import.changes.lock().unwrap();
+        assert_eq!((changes.added_chunks, changes.overwritten_chunks), (1, 0));
+
+        let picked = import.picked.lock().unwrap();
+        assert_eq!(picked[&(0, 0)][&(2, 2)], Picked { key: (0, 20), existed: true, imported: false });
+        assert_eq!(picked[&(0, 0)][&(3, 3)], Picked { key: (0, 20), existed: false, imported: true });
+    }
+}
@@ -0,0 +1,637 @@
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, io, io::{BufReader, Read}};
+use std::path::PathBuf;
+use byteorder::{BigEndian, ReadBytesExt};
+use flate2::read::ZlibDecoder;
+use mc_varint::VarIntRead;
+use nbt::CompoundTag;
+
+use crate::bulk::{session, BulkOptions, DecodedDump, Dispatcher, Dump, Failure, FailureKind};
+use crate::journal::content_hash;
+use crate::models::{block_state_tag, pack_block_states, unpack_block_states, ReadArrayExt, SECTION_VOLUME};
+use crate::packet::{read_chunk_data_body, CHUNK_DATA};
+
+/// Clientbound 1.15 play state packets replayed from a capture
+const BLOCK_CHANGE: i32 = 0x0C;
+const MULTI_BLOCK_CHANGE: i32 = 0x10;
+const UNLOAD_CHUNK: i32 = 0x1E;
+const UPDATE_LIGHT: i32 = 0x25;
+
+/// Largest packet accepted by the protocol
+const MAX_PACKET_LEN: i32 = 2097151;
+/// Largest uncompressed packet accepted by the 1.15 client
+const MAX_UNCOMPRESSED_LEN: i32 = 8 * 1024 * 1024;
+/// Light sections of a chunk, from y -1 to y 16
+const LIGHT_SECTIONS: i32 = 18;
+const LIGHT_LEN: usize = 2048;
+
+/// Reads the length prefixed packets of a capture, with the compressed framing when `compressed`
+struct CaptureReader<R> {
+    reader: R,
+    compressed: bool,
+}
+
+impl<R: Read> CaptureReader<R> {
+    fn next_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut first = [0u8; 1];
+        if self.reader.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let len = i32::from((&first[..]).chain(&mut self.reader).read_var_int()?);
+        if !(0..=MAX_PACKET_LEN).contains(&len) {
+            return Err(invalid(format!("Invalid packet length {}", len)));
+        }
+        let mut frame = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut frame)?;
+        if frame.len() != len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated packet"));
+        }
+        if !self.compressed {
+            return Ok(Some(frame));
+        }
+        let mut r = &frame[..];
+        let data_len = i32::from(r.read_var_int()?);
+        if data_len == 0 {
+            return Ok(Some(r.to_vec()));
+        }
+        if !(0..=MAX_UNCOMPRESSED_LEN).contains(&data_len) {
+            return Err(invalid(format!("Invalid uncompressed length {}", data_len)));
+        }
+        let mut data = Vec::new();
+        ZlibDecoder::new(r).take(data_len as u64).read_to_end(&mut data)?;
+        if data.len() != data_len as usize {
+            return Err(invalid(format!("Packet inflated to {} bytes instead of {}", data.len(), data_len)));
+        }
+        Ok(Some(data))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn air() -> CompoundTag {
+    let mut tag = CompoundTag::new();
+    tag.insert_str("Name", "minecraft:air");
+    tag
+}
+
+/// Editable form of a section tag
+struct Section {
+    palette: Vec<CompoundTag>,
+    /// Palette index of every block of the section
+    blocks: Vec<usize>,
+    sky_light: Option<Vec<i8>>,
+    block_light: Option<Vec<i8>>,
+}
+
+impl Section {
+    fn from_tag(tag: &CompoundTag) -> Section {
+        let mut palette: Vec<CompoundTag> = tag.get_compound_tag_vec("Palette")
+            .map(|palette| palette.into_iter().cloned().collect())
+            .unwrap_or_else(|_| vec![]);
+        if palette.is_empty() {
+            palette.push(air());
+        }
+        let blocks = match tag.get_i64_vec("BlockStates") {
+            Ok(states) => unpack_block_states(&states[..], palette.len()),
+            Err(_) => vec![0; SECTION_VOLUME],
+        };
+        Section {
+            palette,
+            blocks,
+            sky_light: tag.get_i8_vec("SkyLight").ok().cloned(),
+            block_light: tag.get_i8_vec("BlockLight").ok().cloned(),
+        }
+    }
+
+    /// Section tag, only the palette entries still in use are kept
+    fn to_tag(&self, y: i8) -> CompoundTag {
+        let mut used: Vec<Option<usize>> = vec![None; self.palette.len()];
+        let mut palette = vec![];
+        let blocks: Vec<usize> = self.blocks.iter().map(|index| {
+            // Indexes past the palette of a malformed section are read as its first entry
+            let index = if *index < self.palette.len() { *index } else { 0 };
+            *used[index].get_or_insert_with(|| {
+                palette.push(self.palette[index].clone());
+                palette.len() - 1
+            })
+        }).collect();
+        let mut tag = CompoundTag::new();
+        tag.insert_i64_vec("BlockStates", pack_block_states(&blocks, palette.len()));
+        tag.insert_compound_tag_vec("Palette", palette);
+        tag.insert_i8_vec("SkyLight", self.sky_light.clone().unwrap_or_else(|| vec![0; LIGHT_LEN]));
+        if let Some(block_light) = &self.block_light {
+            tag.insert_i8_vec("BlockLight", block_light.clone());
+        }
+        tag.insert_i8("Y", y);
+        tag
+    }
+
+    fn set_block(&mut self, x: i32, y: i32, z: i32, block: CompoundTag) {
+        let index = match self.palette.iter().position(|e| *e == block) {
+            Some(index) => index,
+            None => {
+                self.palette.push(block);
+                self.palette.len() - 1
+            },
+        };
+        self.blocks[((y & 15) * 256 + (z & 15) * 16 + (x & 15)) as usize] = index;
+    }
+}
+
+/// Light arrays of an Update Light packet, by section y
+#[derive(Default)]
+struct Light {
+    sky: Vec<(i8, Option<Vec<i8>>)>,
+    block: Vec<(i8, Option<Vec<i8>>)>,
+}
+
+/// A chunk loaded by the client during the capture, imported once unloaded or at the end of the capture
+struct LoadedChunk {
+    /// `<capture>:<packet>` of the Chunk Data packet
+    label: PathBuf,
+    /// Hash of the packets which built the chunk
+    hash: u64,
+    packet: usize,
+    sections: BTreeMap<i8, Section>,
+    chunk: CompoundTag,
+}
+
+impl LoadedChunk {
+    fn update_hash(&mut self, data: &[u8]) {
+        self.hash = content_hash(&[&self.hash.to_be_bytes()[..], data].concat());
+    }
+
+    fn apply_light(&mut self, light: &Light) {
+        for (y, sky) in light.sky.iter() {
+            if let Some(section) = self.sections.get_mut(y) {
+                section.sky_light = Some(sky.clone().unwrap_or_else(|| vec![0; LIGHT_LEN]));
+            }
+        }
+        for (y, block) in light.block.iter() {
+            if let Some(section) = self.sections.get_mut(y) {
+                section.block_light = Some(block.clone().unwrap_or_else(|| vec![0; LIGHT_LEN]));
+            }
+        }
+    }
+
+    fn into_dump(self, x: i32, z: i32) -> DecodedDump {
+        let mut chunk = self.chunk;
+        let mut level = chunk.get_compound_tag("Level").ok().cloned().unwrap_or_else(CompoundTag::new);
+        level.insert_compound_tag_vec("Sections", self.sections.iter().map(|(y, section)| section.to_tag(*y)).collect());
+        chunk.insert_compound_tag("Level", level);
+        DecodedDump {
+            label: self.label,
+            hash: self.hash,
+            x,
+            z,
+            sections: self.sections.len() as u32,
+            timestamp: self.packet as u64,
+            chunk,
+        }
+    }
+}
+
+fn read_sections(chunk: &CompoundTag) -> BTreeMap<i8, Section> {
+    chunk.get_compound_tag("Level")
+        .and_then(|level| level.get_compound_tag_vec("Sections"))
+        .map(|sections| sections.into_iter()
+            .filter_map(|tag| Some((tag.get_i8("Y").ok()?, Section::from_tag(tag))))
+            .collect())
+        .unwrap_or_default()
+}
+
+/// Block position packed in a long, x (26 bits), z (26 bits) then y (12 bits)
+fn block_position(value: i64) -> (i32, i32, i32) {
+    ((value >> 38) as i32, (value << 52 >> 52) as i32, (value << 26 >> 38) as i32)
+}
+
+/// Chunks of the world as seen by the client while the capture was recorded
+struct Replay<'a> {
+    capture: PathBuf,
+    dispatcher: &'a Dispatcher,
+    loaded: HashMap<(i32, i32), LoadedChunk>,
+    /// Light sent before its chunk
+    pending_light: HashMap<(i32, i32), Light>,
+}
+
+impl<'a> Replay<'a> {
+    fn label(&self, packet: usize) -> PathBuf {
+        PathBuf::from(format!("{}:{}", self.capture.display(), packet))
+    }
+
+    fn handle(&mut self, packet: usize, data: &[u8]) -> Result<(), (FailureKind, String)> {
+        let parse = |e: io::Error| (FailureKind::Parse, e.to_string());
+        let mut r = data;
+        let id = i32::from(r.read_var_int().map_err(parse)?);
+        match id {
+            CHUNK_DATA => {
+                let chunk = read_chunk_data_body(&mut r).map_err(parse)?;
+                let (x, z, ground_up) = (chunk.x, chunk.z, chunk.ground_up);
+                if !ground_up && !self.loaded.contains_key(&(x, z)) {
+                    // Only the sections of the packet, the client drops it without the rest of the chunk
+                    return Ok(());
+                }
+                let chunk = chunk.into_compound_tag().map_err(|e| (FailureKind::Decode, e.to_string()))?;
+                let sections = read_sections(&chunk);
+                match self.loaded.get_mut(&(x, z)) {
+                    // Only the sections of the packet are sent again
+                    Some(loaded) if !ground_up => {
+                        loaded.sections.extend(sections);
+                        loaded.update_hash(data);
+                    },
+                    _ => {
+                        let mut loaded = LoadedChunk {
+                            label: self.label(packet),
+                            hash: content_hash(data),
+                            packet,
+                            sections,
+                            chunk,
+                        };
+                        if let Some(light) = self.pending_light.remove(&(x, z)) {
+                            loaded.apply_light(&light);
+                        }
+                        self.loaded.insert((x, z), loaded);
+                    },
+                }
+            },
+            UNLOAD_CHUNK => {
+                let x = r.read_i32::<BigEndian>().map_err(parse)?;
+                let z = r.read_i32::<BigEndian>().map_err(parse)?;
+                self.unload(x, z);
+            },
+            BLOCK_CHANGE => {
+                let (x, y, z) = block_position(r.read_i64::<BigEndian>().map_err(parse)?);
+                let state = i32::from(r.read_var_int().map_err(parse)?);
+                self.set_block(x, y, z, state, data)?;
+            },
+            MULTI_BLOCK_CHANGE => {
+                let chunk_x = r.read_i32::<BigEndian>().map_err(parse)?;
+                let chunk_z = r.read_i32::<BigEndian>().map_err(parse)?;
+                let count = i32::from(r.read_var_int().map_err(parse)?);
+                for _ in 0..count {
+                    let horizontal = r.read_u8().map_err(parse)? as i32;
+                    let y = r.read_u8().map_err(parse)? as i32;
+                    let state = i32::from(r.read_var_int().map_err(parse)?);
+                    self.set_block(chunk_x * 16 + (horizontal >> 4), y, chunk_z * 16 + (horizontal & 15), state, data)?;
+                }
+            },
+            UPDATE_LIGHT => {
+                let x = i32::from(r.read_var_int().map_err(parse)?);
+                let z = i32::from(r.read_var_int().map_err(parse)?);
+                let masks = r.read_varint_array(4).map_err(parse)?;
+                let mut light = Light::default();
+                for (mask, empty, arrays) in [(masks[0], masks[2], &mut light.sky), (masks[1], masks[3], &mut light.block)].iter_mut() {
+                    for i in 0..LIGHT_SECTIONS {
+                        // Bit 0 is the section under the world
+                        let y = (i - 1) as i8;
+                        if (*mask >> i) & 1 != 0 {
+                            let len = i32::from(r.read_var_int().map_err(parse)?);
+                            if len as usize != LIGHT_LEN {
+                                return Err((FailureKind::Parse, format!("Invalid light array length {}", len)));
+                            }
+                            let array = r.read_u8_array(LIGHT_LEN).map_err(parse)?;
+                            arrays.push((y, Some(array.into_iter().map(|e| e as i8).collect())));
+                        } else if (*empty >> i) & 1 != 0 {
+                            arrays.push((y, None));
+                        }
+                    }
+                }
+                match self.loaded.get_mut(&(x, z)) {
+                    Some(loaded) => {
+                        loaded.apply_light(&light);
+                        loaded.update_hash(data);
+                    },
+                    None => {
+                        self.pending_light.insert((x, z), light);
+                    },
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn set_block(&mut self, x: i32, y: i32, z: i32, state: i32, data: &[u8]) -> Result<(), (FailureKind, String)> {
+        let loaded = match self.loaded.get_mut(&(x >> 4, z >> 4)) {
+            Some(loaded) => loaded,
+            // Changes of chunks the client doesn't hold are ignored by the client too
+            None => return Ok(()),
+        };
+        if !(0..=255).contains(&y) {
+            return Err((FailureKind::Parse, format!("Block change out of the world at {} {} {}", x, y, z)));
+        }
+        let block = block_state_tag(state).ok_or_else(|| (FailureKind::Decode, format!("Unknown block state {}", state)))?;
+        loaded.sections.entry((y >> 4) as i8)
+            .or_insert_with(|| Section {
+                palette: vec![air()],
+                blocks: vec![0; SECTION_VOLUME],
+                sky_light: None,
+                block_light: None,
+            })
+            .set_block(x, y, z, block);
+        loaded.update_hash(data);
+        Ok(())
+    }
+
+    fn unload(&mut self, x: i32, z: i32) {
+        if let Some(loaded) = self.loaded.remove(&(x, z)) {
+            self.dispatcher.send(x, z, Dump::Decoded(loaded.into_dump(x, z)));
+        }
+    }
+
+    /// Import the chunks still loaded at the end of the capture
+    fn finish(mut self) {
+        let positions: Vec<_> = self.loaded.keys().cloned().collect();
+        for (x, z) in positions {
+            self.unload(x, z);
+        }
+    }
+}
+
+/// Replay a packet capture into the world through the `bulk` import, `compression_threshold`
+/// enables the compressed framing
+pub fn import_capture(output: &str, capture: &str, compression_threshold: Option<i32>, options: BulkOptions) -> io::Result<Vec<Failure>> {
+    let capture = fs::canonicalize(capture)?;
+    let mut reader = CaptureReader {
+        reader: BufReader::new(fs::File::open(&capture)?),
+        compressed: compression_threshold.map(|threshold| threshold >= 0).unwrap_or(false),
+    };
+    session(output, options, 0, |import| {
+        let dispatcher = Dispatcher::new(import);
+        let mut replay = Replay {
+            capture: capture.clone(),
+            dispatcher: &dispatcher,
+            loaded: HashMap::new(),
+            pending_light: HashMap::new(),
+        };
+        let mut packet = 0;
+        loop {
+            match reader.next_packet() {
+                Ok(Some(data)) => {
+                    if let Err((kind, reason)) = replay.handle(packet, &data) {
+                        dispatcher.fail(replay.label(packet), kind, reason);
+                    }
+                    packet += 1;
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    // The chunks read so far are still imported
+                    dispatcher.fail_input(replay.label(packet), e.to_string());
+                    break;
+                },
+            }
+        }
+        info!("Replayed {} packets from {}", packet, capture.display());
+        replay.finish();
+        dispatcher.finish();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::Path;
+    use byteorder::WriteBytesExt;
+    use flate2::{write::ZlibEncoder, Compression};
+    use serde_json::json;
+    use crate::bulk::Pick;
+    use crate::conflict::ConflictPolicy;
+    use crate::nbt_json::write_nbt;
+    use crate::region::Region;
+
+    fn encode(x: i32, y: i32, z: i32) -> i64 {
+        ((x as i64 & 0x3FF_FFFF) << 38) | ((z as i64 & 0x3FF_FFFF) << 12) | (y as i64 & 0xFFF)
+    }
+
+    fn varint(value: i32) -> Vec<u8> {
+        let mut value = value as u32;
+        let mut data = vec![];
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                data.push(byte);
+                return data;
+            }
+            data.push(byte | 0x80);
+        }
+    }
+
+    fn frame(packet: &[u8]) -> Vec<u8> {
+        let mut data = varint(packet.len() as i32);
+        data.extend_from_slice(packet);
+        data
+    }
+
+    /// Ground up Chunk Data packet with a single section of air at y 0
+    fn chunk_data(x: i32, z: i32) -> Vec<u8> {
+        let mut data = varint(CHUNK_DATA);
+        data.write_i32::<BigEndian>(x).unwrap();
+        data.write_i32::<BigEndian>(z).unwrap();
+        data.push(1);
+        data.push(1);
+        write_nbt(&mut data, &json!({"type": "compound", "name": "", "value": {}})).unwrap();
+        data.extend_from_slice(&[0; 1024 * 4]);
+        // Block count, bits per block, palette of air and no block states
+        let section = [0, 0, 4, 1, 0, 0];
+        data.extend(varint(section.len() as i32));
+        data.extend_from_slice(&section);
+        // No block entities
+        data.push(0);
+        data
+    }
+
+    fn unload_chunk(x: i32, z: i32) -> Vec<u8> {
+        let mut data = varint(UNLOAD_CHUNK);
+        data.write_i32::<BigEndian>(x).unwrap();
+        data.write_i32::<BigEndian>(z).unwrap();
+        data
+    }
+
+    fn block_change(x: i32, y: i32, z: i32, state: i32) -> Vec<u8> {
+        let mut data = varint(BLOCK_CHANGE);
+        data.write_i64::<BigEndian>(encode(x, y, z)).unwrap();
+        data.extend(varint(state));
+        data
+    }
+
+    /// `changes` are the horizontal position in the chunk (x << 4 | z), y and block state
+    fn multi_block_change(chunk_x: i32, chunk_z: i32, changes: &[(u8, u8, i32)]) -> Vec<u8> {
+        let mut data = varint(MULTI_BLOCK_CHANGE);
+        data.write_i32::<BigEndian>(chunk_x).unwrap();
+        data.write_i32::<BigEndian>(chunk_z).unwrap();
+        data.extend(varint(changes.len() as i32));
+        for (horizontal, y, state) in changes {
+            data.push(*horizontal);
+            data.push(*y);
+            data.extend(varint(*state));
+        }
+        data
+    }
+
+    /// `masks` are the sky, block, empty sky and empty block masks, `arrays` the light arrays of the set bits
+    fn update_light(x: i32, z: i32, masks: [i32; 4], arrays: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = varint(UPDATE_LIGHT);
+        data.extend(varint(x));
+        data.extend(varint(z));
+        for mask in masks.iter() {
+            data.extend(varint(*mask));
+        }
+        for array in arrays {
+            data.extend(varint(array.len() as i32));
+            data.extend_from_slice(array);
+        }
+        data
+    }
+
+    /// Replay `packets` into `world`, `f` is called with the chunks still loaded at the end of the capture
+    fn replay<F: FnOnce(&mut Replay)>(world: &Path, packets: &[Vec<u8>], f: F) {
+        if std::env::var("PALETTE").is_err() {
+            std::env::set_var("PALETTE", "./res/blocks-1.15.0.json");
+        }
+        let options = BulkOptions {
+            jobs: 1,
+            resume: false,
+            quarantine: None,
+            on_conflict: ConflictPolicy::Overwrite,
+            pick: Pick::Latest,
+            dry_run: false,
+            backup: false,
+        };
+        let failures = session(world.to_str().unwrap(), options, 0, |import| {
+            let dispatcher = Dispatcher::new(import);
+            let mut replay = Replay {
+                capture: PathBuf::from("capture"),
+                dispatcher: &dispatcher,
+                loaded: HashMap::new(),
+                pending_light: HashMap::new(),
+            };
+            for (packet, data) in packets.iter().enumerate() {
+                replay.handle(packet, data).unwrap();
+            }
+            f(&mut replay);
+            replay.finish();
+            dispatcher.finish();
+        }).unwrap();
+        assert!(failures.is_empty());
+    }
+
+    fn block_name(section: &Section, x: i32, y: i32, z: i32) -> &str {
+        let index = section.blocks[((y & 15) * 256 + (z & 15) * 16 + (x & 15)) as usize];
+        section.palette[index].get_str("Name").unwrap()
+    }
+
+    #[test]
+    fn compressed_framing() {
+        let small = unload_chunk(1, 2);
+        let big = vec![CHUNK_DATA as u8; 300];
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(&big).unwrap();
+        let zlib = zlib.finish().unwrap();
+        // Under the threshold, sent as is after a zero data length
+        let mut capture = frame(&[&[0][..], &small].concat());
+        capture.extend(frame(&[varint(big.len() as i32), zlib.clone()].concat()));
+        let mut reader = CaptureReader { reader: &capture[..], compressed: true };
+        assert_eq!(reader.next_packet().unwrap(), Some(small.clone()));
+        assert_eq!(reader.next_packet().unwrap(), Some(big.clone()));
+        assert_eq!(reader.next_packet().unwrap(), None);
+
+        let capture = frame(&small);
+        let mut reader = CaptureReader { reader: &capture[..], compressed: false };
+        assert_eq!(reader.next_packet().unwrap(), Some(small));
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let read = |capture: &[u8], compressed: bool| CaptureReader { reader: capture, compressed }.next_packet().unwrap_err().kind();
+        let capture = frame(&[1, 2, 3]);
+        assert_eq!(read(&capture[..3], false), io::ErrorKind::UnexpectedEof);
+        // Negative length
+        assert_eq!(read(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F], false), io::ErrorKind::InvalidData);
+        // Inflates to less than the announced length
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(&[1, 2, 3]).unwrap();
+        let capture = frame(&[varint(4), zlib.finish().unwrap()].concat());
+        assert_eq!(read(&capture, true), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unload_imports_the_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let packets = vec![
+            chunk_data(1, 2),
+            block_change(16 + 3, 70, 32 + 4, 1),
+            chunk_data(5, 5),
+            unload_chunk(1, 2),
+        ];
+        replay(world, &packets, |replay| {
+            assert!(!replay.loaded.contains_key(&(1, 2)));
+            assert!(replay.loaded.contains_key(&(5, 5)));
+        });
+        let region = Region::open(world, 0, 0).unwrap();
+        let chunk = region.load_chunk(1, 2).unwrap().unwrap();
+        let sections = read_sections(&chunk);
+        assert_eq!(sections.keys().cloned().collect::<Vec<_>>(), vec![0, 4]);
+        assert_eq!(block_name(&sections[&4], 3, 70, 4), "minecraft:stone");
+        // Still loaded at the end of the capture
+        assert!(region.contains(5, 5));
+    }
+
+    #[test]
+    fn block_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let packets = vec![
+            chunk_data(0, 0),
+            block_change(1, 2, 3, 1),
+            multi_block_change(0, 0, &[(0x45, 17, 2), (0x00, 2, 1)]),
+            // Chunk the client doesn't hold
+            block_change(100, 5, 100, 1),
+        ];
+        replay(dir.path(), &packets, |replay| {
+            assert!(replay.handle(4, &block_change(1, 300, 3, 1)).is_err());
+            assert!(replay.handle(5, &block_change(1, 2, 3, -5)).is_err());
+            assert_eq!(replay.loaded.len(), 1);
+            let sections = &replay.loaded[&(0, 0)].sections;
+            assert_eq!(sections.keys().cloned().collect::<Vec<_>>(), vec![0, 1]);
+            assert_eq!(block_name(&sections[&0], 1, 2, 3), "minecraft:stone");
+            assert_eq!(block_name(&sections[&0], 0, 2, 0), "minecraft:stone");
+            assert_eq!(block_name(&sections[&1], 4, 17, 5), "minecraft:granite");
+            assert_eq!(block_name(&sections[&1], 4, 16, 5), "minecraft:air");
+        });
+    }
+
+    #[test]
+    fn light() {
+        let dir = tempfile::tempdir().unwrap();
+        let packets = vec![
+            // Sent before the chunk, section y 0 is bit 1
+            update_light(1, 0, [0b10, 0, 0, 0b10], &[vec![0x11; LIGHT_LEN]]),
+            chunk_data(1, 0),
+        ];
+        replay(dir.path(), &packets, |replay| {
+            let section = &replay.loaded[&(1, 0)].sections[&0];
+            assert_eq!(section.sky_light, Some(vec![0x11; LIGHT_LEN]));
+            assert_eq!(section.block_light, Some(vec![0; LIGHT_LEN]));
+
+            // Section y 1 is not loaded, its array is read and left out
+            replay.handle(2, &update_light(1, 0, [0, 0b110, 0b10, 0], &[vec![0x22; LIGHT_LEN], vec![0x33; LIGHT_LEN]])).unwrap();
+            let sections = &replay.loaded[&(1, 0)].sections;
+            assert_eq!(sections.len(), 1);
+            assert_eq!(sections[&0].sky_light, Some(vec![0; LIGHT_LEN]));
+            assert_eq!(sections[&0].block_light, Some(vec![0x22; LIGHT_LEN]));
+
+            assert!(replay.handle(3, &update_light(1, 0, [0b10, 0, 0, 0], &[vec![0; 10]])).is_err());
+        });
+    }
+
+    #[test]
+    fn positions() {
+        for (x, y, z) in [(1, 2, 3), (-1, 255, -1), (-33_554_432, 0, 33_554_431), (100, 64, -200)].iter().cloned() {
+            assert_eq!(block_position(encode(x, y, z)), (x, y, z));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::str::FromStr;
+use nbt::CompoundTag;
+
+use crate::models::unpack_block_states;
+
+const AIR_BLOCKS: [&str; 3] = ["minecraft:air", "minecraft:cave_air", "minecraft:void_air"];
+
+/// What to do when a dump targets a chunk which already exists in the world
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// Replace the existing chunk
+    Overwrite,
+    /// Keep the existing chunk
+    Skip,
+    /// Keep the chunk with the most non-air blocks
+    MostBlocks,
+    /// Keep the existing chunk, only filling its missing or empty sections from the dump
+    Merge,
+}
+
+pub const CONFLICT_POLICIES: [&str; 4] = ["overwrite", "skip", "most-blocks", "merge"];
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Overwrite
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "most-blocks" => Ok(ConflictPolicy::MostBlocks),
+            "merge" => Ok(ConflictPolicy::Merge),
+            _ => Err(format!("Unknown conflict policy {}", s)),
+        }
+    }
+}
+
+impl ConflictPolicy {
+    /// Chunk to write in place of `existing`, `None` when the existing chunk is kept as is
+    pub fn resolve(&self, existing: Option<CompoundTag>, new: CompoundTag) -> Option<CompoundTag> {
+        let existing = match existing {
+            Some(existing) => existing,
+            None => return Some(new),
+        };
+        match self {
+            ConflictPolicy::Overwrite => Some(new),
+            ConflictPolicy::Skip => None,
+            ConflictPolicy::MostBlocks => {
+                if count_blocks(&new) > count_blocks(&existing) {
+                    Some(new)
+                } else {
+                    None
+                }
+            },
+            ConflictPolicy::Merge => merge_sections(&existing, &new),
+        }
+    }
+}
+
+fn is_air(name: &str) -> bool {
+    AIR_BLOCKS.contains(&name)
+}
+
+/// Number of non-air blocks of a section
+fn count_section_blocks(section: &CompoundTag) -> usize {
+    let palette: Vec<bool> = match section.get_compound_tag_vec("Palette") {
+        Ok(palette) => palette.into_iter().map(|e| is_air(e.get_str("Name").unwrap_or("minecraft:air"))).collect(),
+        Err(_) => return 0,
+    };
+    if palette.iter().all(|air| *air) {
+        return 0;
+    }
+    let states = match section.get_i64_vec("BlockStates") {
+        Ok(states) => states,
+        Err(_) => return 0,
+    };
+    unpack_block_states(&states[..], palette.len())
+        .into_iter()
+        .filter(|index| palette.get(*index).map(|air| !*air).unwrap_or(false))
+        .count()
+}
+
+/// Number of non-air blocks of a chunk
+pub fn count_blocks(chunk: &CompoundTag) -> usize {
+    chunk.get_compound_tag("Level")
+        .and_then(|level| level.get_compound_tag_vec("Sections"))
+        .map(|sections| sections.into_iter().map(count_section_blocks).sum())
+        .unwrap_or(0)
+}
+
+/// Fill the missing or empty sections of `existing` with the ones of `new`, `None` if nothing was filled
+fn merge_sections(existing: &CompoundTag, new: &CompoundTag) -> Option<CompoundTag> {
+    let existing_level = existing.get_compound_tag("Level").ok()?;
+    let mut sections: Vec<CompoundTag> = existing_level.get_compound_tag_vec("Sections")
+        .map(|sections| sections.into_iter().cloned().collect())
+        .unwrap_or_else(|_| vec![]);
+    let new_sections = new.get_compound_tag("Level")
+        .and_then(|level| level.get_compound_tag_vec("Sections"))
+        .ok()?;
+    let mut filled = false;
+    for section in new_sections {
+        let y = match section.get_i8("Y") {
+            Ok(y) => y,
+            Err(_) => continue,
+        };
+        if count_section_blocks(section) == 0 {
+            continue;
+        }
+        match sections.iter().position(|e| e.get_i8("Y").ok() == Some(y)) {
+            Some(i) if count_section_blocks(&sections[i]) == 0 => sections[i] = section.clone(),
+            Some(_) => continue,
+            None => sections.push(section.clone()),
+        }
+        filled = true;
+    }
+    if !filled {
+        return None;
+    }
+    sections.sort_by_key(|e| e.get_i8("Y").unwrap_or(0));
+    let mut level = existing_level.clone();
+    level.insert_compound_tag_vec("Sections", sections);
+    let mut chunk = existing.clone();
+    chunk.insert_compound_tag("Level", level);
+    Some(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{pack_block_states, SECTION_VOLUME};
+
+    /// Section `y` with `count` blocks of `block`, the rest is air
+    fn section(y: i8, block: &str, count: usize) -> CompoundTag {
+        let palette: Vec<CompoundTag> = ["minecraft:air", block].iter()
+            .map(|name| {
+                let mut entry = CompoundTag::new();
+                entry.insert_str("Name", name);
+                entry
+            })
+            .collect();
+        let indexes: Vec<usize> = (0..SECTION_VOLUME).map(|i| (i < count) as usize).collect();
+        let mut section = CompoundTag::new();
+        section.insert_i8("Y", y);
+        section.insert_compound_tag_vec("Palette", palette);
+        section.insert_i64_vec("BlockStates", pack_block_states(&indexes, 2));
+        section
+    }
+
+    fn chunk(sections: Vec<CompoundTag>) -> CompoundTag {
+        let mut level = CompoundTag::new();
+        level.insert_compound_tag_vec("Sections", sections);
+        let mut chunk = CompoundTag::new();
+        chunk.insert_compound_tag("Level", level);
+        chunk
+    }
+
+    /// Y, block and number of blocks of each section
+    fn sections(chunk: &CompoundTag) -> Vec<(i8, String, usize)> {
+        chunk.get_compound_tag("Level").unwrap().get_compound_tag_vec("Sections").unwrap().into_iter()
+            .map(|section| {
+                let palette = section.get_compound_tag_vec("Palette").unwrap();
+                (section.get_i8("Y").unwrap(), palette[1].get_str("Name").unwrap().to_string(), count_section_blocks(section))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_fills_missing_and_empty_sections() {
+        let existing = chunk(vec![section(0, "minecraft:stone", 100), section(1, "minecraft:stone", 0)]);
+        let new = chunk(vec![section(2, "minecraft:dirt", 5), section(1, "minecraft:dirt", 10), section(0, "minecraft:dirt", 4096)]);
+        let merged = merge_sections(&existing, &new).unwrap();
+        assert_eq!(sections(&merged), vec![
+            (0, "minecraft:stone".to_string(), 100),
+            (1, "minecraft:dirt".to_string(), 10),
+            (2, "minecraft:dirt".to_string(), 5),
+        ]);
+    }
+
+    #[test]
+    fn merge_without_anything_to_fill() {
+        let existing = chunk(vec![section(0, "minecraft:stone", 100)]);
+        let new = chunk(vec![section(0, "minecraft:dirt", 200), section(1, "minecraft:dirt", 0)]);
+        assert!(merge_sections(&existing, &new).is_none());
+        assert!(ConflictPolicy::Merge.resolve(Some(existing), new).is_none());
+    }
+
+    #[test]
+    fn count() {
+        assert_eq!(count_blocks(&chunk(vec![section(0, "minecraft:stone", 100), section(1, "minecraft:cave_air", 4096)])), 100);
+        assert_eq!(count_blocks(&CompoundTag::new()), 0);
+    }
+
+    #[test]
+    fn policies() {
+        let small = || chunk(vec![section(0, "minecraft:stone", 10)]);
+        let big = || chunk(vec![section(0, "minecraft:stone", 20)]);
+        for policy in [ConflictPolicy::Overwrite, ConflictPolicy::Skip, ConflictPolicy::MostBlocks, ConflictPolicy::Merge].iter() {
+            assert!(policy.resolve(None, small()).is_some(), "{:?} without an existing chunk", policy);
+        }
+        assert_eq!(ConflictPolicy::Overwrite.resolve(Some(big()), small()).map(|e| count_blocks(&e)), Some(10));
+        assert!(ConflictPolicy::Skip.resolve(Some(small()), big()).is_none());
+        assert_eq!(ConflictPolicy::MostBlocks.resolve(Some(small()), big()).map(|e| count_blocks(&e)), Some(20));
+        assert!(ConflictPolicy::MostBlocks.resolve(Some(big()), small()).is_none());
+    }
+
+    #[test]
+    fn parse() {
+        for name in CONFLICT_POLICIES.iter() {
+            assert!(name.parse::<ConflictPolicy>().is_ok());
+        }
+        assert!("replace".parse::<ConflictPolicy>().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fs, io, io::{BufWriter, Write}};
+use std::path::{Path, PathBuf};
+
+use crate::binary;
+use crate::bulk::{get_chunks_fmap, get_streams};
+use crate::input::{read_dump, RawDump};
+use crate::journal::content_hash;
+use crate::models::PacketChunk;
+
+/// Rewrite the dumps of `patch` (anything `bulk` accepts) as binary dumps in `destination`,
+/// returning the number of converted dumps and the number of failures
+pub fn convert(patch: &str, destination: &str) -> io::Result<(usize, usize)> {
+    let patch = PathBuf::from(patch);
+    let destination = PathBuf::from(destination);
+    fs::create_dir_all(&destination)?;
+    let mut converted = 0;
+    let mut failed = 0;
+    let mut convert_one = |entry: Result<RawDump, (PathBuf, io::Error)>| {
+        let result = entry.and_then(|raw| {
+            convert_dump(&raw, &destination).map_err(|e| (raw.label, e))
+        });
+        match result {
+            Ok(true) => converted += 1,
+            Ok(false) => {},
+            Err((path, e)) => {
+                warn!("{}: {}", path.display(), e);
+                failed += 1;
+            },
+        }
+    };
+    if patch != Path::new("-") {
+        for job in get_chunks_fmap(&patch)? {
+            for path in job.paths {
+                convert_one(read_dump(&path).map_err(|e| (path, e)));
+            }
+        }
+    }
+    for input in get_streams(&patch)? {
+        if let Err(e) = input.for_each(&mut convert_one) {
+            convert_one(Err((input.path(), e)));
+        }
+    }
+    info!("Converted {} dumps to {}, {} failed", converted, destination.display(), failed);
+    Ok((converted, failed))
+}
+
+/// Write one binary dump, `false` for archive entries which are not dumps
+fn convert_dump(raw: &RawDump, destination: &Path) -> io::Result<bool> {
+    let mut chunk = match PacketChunk::parse(&raw.data) {
+        Ok(chunk) => chunk,
+        Err(_) if raw.named && raw.position().is_none() => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    // The binary dump gets a new modification time, keep the capture time of the original
+    chunk.timestamp = chunk.timestamp.or(raw.mtime);
+    let target = destination.join(dump_name(raw, &chunk));
+    let mut file = BufWriter::new(fs::File::create(&target)?);
+    binary::write(&mut file, &chunk)?;
+    file.flush()?;
+    Ok(true)
+}
+
+/// Name of the binary dump of `raw`, the original name (or position) and the hash of the content
+fn dump_name(raw: &RawDump, chunk: &PacketChunk) -> String {
+    let name = match raw.named {
+        true => raw.label.file_name().and_then(|e| e.to_str()).and_then(|e| e.split('.').next()).map(str::to_string),
+        false => None,
+    }.unwrap_or_else(|| format!("{}_{}", chunk.x, chunk.z));
+    format!("{}_{:016x}.{}", name, content_hash(&raw.data), binary::EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn raw(label: &str, named: bool, x: i32) -> RawDump {
+        let data = json!({"x": x, "z": 2, "groundUp": true, "bitMap": 0, "chunkData": {"type": "Buffer", "data": []}, "blockEntities": []});
+        RawDump {
+            label: PathBuf::from(label),
+            data: data.to_string().into_bytes(),
+            named,
+            mtime: None,
+        }
+    }
+
+    fn name(raw: &RawDump) -> String {
+        dump_name(raw, &PacketChunk::parse(&raw.data).unwrap())
+    }
+
+    #[test]
+    fn names() {
+        let a = name(&raw("a/1_2.json", true, 1));
+        assert!(a.starts_with("1_2_") && a.ends_with(".d2m"), "{}", a);
+        assert_eq!(crate::input::dump_xz(Path::new(&a)), Some((1, 2)));
+        // Same name in another directory, or compressed, with another content
+        assert_ne!(name(&raw("b/1_2.json", true, 3)), a);
+        assert_ne!(name(&raw("a/1_2.json.gz", true, 3)), a);
+        // Converting the same dump again gives the same name
+        assert_eq!(name(&raw("b/1_2.json.gz", true, 1)), a);
+        // Unnamed dumps are named after their position
+        let line = name(&raw("a/dumps.ndjson:3", false, 5));
+        assert!(line.starts_with("5_2_"), "{}", line);
+    }
+}
@@ -0,0 +1,237 @@
+use actix::prelude::*;
+use std::collections::{BTreeMap, btree_map};
+use nbt::CompoundTag;
+use regex::Regex;
+use std::path::PathBuf;
+
+use crate::region::{Region, RegionFile};
+use crate::models::PacketChunk;
+
+/// What the `find` subcommand is looking for
+#[derive(Clone, Debug, Default)]
+pub struct FindQuery {
+    /// Block names to look for in section palettes
+    pub blocks: Vec<String>,
+    /// Item ids to look for in containers (chests, shulkers, barrels ...)
+    pub items: Vec<String>,
+    /// Minimum number of matching items in a single container
+    pub min_count: i32,
+    /// Sign text, matched as a substring of any line
+    pub sign: Option<String>,
+    /// Sign text, matched as a regex against any line
+    pub sign_regex: Option<Regex>,
+    /// Mob types to look for in spawners
+    pub spawners: Vec<String>,
+}
+
+impl FindQuery {
+    pub fn wants_tile_entities(&self) -> bool {
+        !self.items.is_empty() || self.sign.is_some() || self.sign_regex.is_some() || !self.spawners.is_empty()
+    }
+}
+
+pub struct FindActor {
+    query: FindQuery,
+    path: String,
+}
+
+impl FindActor {
+    pub fn new(query: FindQuery, path: String) -> Self {
+        Self {
+            query,
+            path,
+        }
+    }
+}
+
+/// Chunks to scan, either a region file of a world or a group of JSON dumps decoded in memory
+#[derive(Debug)]
+pub enum ChunkSource {
+    World(RegionFile),
+    Dump(Vec<PathBuf>),
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct FindRequest(pub ChunkSource);
+
+impl Handler<FindRequest> for FindActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: FindRequest, _ctx: &mut SyncContext<Self>) {
+        let query = &self.query;
+        for_each_chunk(&self.path, &msg.0, |cx, cz, chunk| {
+            find(cx * 16, cz * 16, &chunk, query);
+        });
+    }
+}
+
+/// Load every chunk of a source, calling `f` with the chunk coordinates and its content
+pub fn for_each_chunk<F: FnMut(i32, i32, CompoundTag)>(path: &str, source: &ChunkSource, mut f: F) {
+    match source {
+        ChunkSource::World(file) => {
+            let region = match Region::open(path, file.x, file.z) {
+                Ok(region) => region,
+                Err(e) => {
+                    warn!("r.{}.{}.mca {}", file.x, file.z, e);
+                    return;
+                },
+            };
+            for cx in (0..32).map(|cx| cx + (region.x * 32)) {
+                for cz in (0..32).map(|cz| cz + (region.z * 32)) {
+                    match region.load_chunk(cx, cz) {
+                        Ok(Some(chunk)) => f(cx, cz, chunk),
+                        Ok(None) => {},
+                        Err(e) => warn!("{}:{} {}", cx, cz, e),
+                    }
+                }
+            }
+        },
+        ChunkSource::Dump(paths) => {
+            for path in paths {
+                let chunk = PacketChunk::read_from(path).and_then(|chunk| {
+                    let (cx, cz) = (chunk.x, chunk.z);
+                    Ok((cx, cz, chunk.into_compound_tag()?))
+                });
+                match chunk {
+                    Ok((cx, cz, chunk)) => f(cx, cz, chunk),
+                    Err(e) => warn!("{}: {}", path.display(), e),
+                }
+            }
+        },
+    }
+}
+
+impl actix::Actor for FindActor {
+    type Context = SyncContext<Self>;
+}
+
+pub fn find(x: i32, z: i32, tag: &CompoundTag, query: &FindQuery) -> Option<()> {
+    fn find_in_section(x: i32, z: i32, section: &CompoundTag, target: &[String]) -> Option<()> {
+        let palette = section.get_compound_tag_vec("Palette").ok()?;
+        let mut found = BTreeMap::new();
+        for item in palette {
+            for target in target {
+                if item.get_str("Name").ok() == Some(target.as_str()) {
+                    match found.entry(target) {
+                        btree_map::Entry::Occupied(mut e) => {
+                            *e.get_mut() += 1;
+                        },
+                        btree_map::Entry::Vacant(e) => {
+                            e.insert(1);
+                        }
+                    };
+                }
+            }
+        }
+        if !found.is_empty() {
+            for (k,v) in found {
+                info!("{} 100 {} -> Found {} {}", x, z, v, k);
+            }
+        }
+        Some(())
+    }
+
+    let level = tag.get_compound_tag("Level").ok()?;
+    if !query.blocks.is_empty() {
+        if let Ok(sections) = level.get_compound_tag_vec("Sections") {
+            for section in sections {
+                let _ = find_in_section(x, z, section, &query.blocks[..]);
+            }
+        }
+    }
+    if query.wants_tile_entities() {
+        for tile_entity in level.get_compound_tag_vec("TileEntities").ok()? {
+            let _ = find_in_tile_entity(tile_entity, query);
+        }
+    }
+    Some(())
+}
+
+fn find_in_tile_entity(tile_entity: &CompoundTag, query: &FindQuery) -> Option<()> {
+    let id = tile_entity.get_str("id").ok()?;
+    let x = tile_entity.get_i32("x").ok()?;
+    let y = tile_entity.get_i32("y").ok()?;
+    let z = tile_entity.get_i32("z").ok()?;
+    if !query.items.is_empty() {
+        if let Ok(items) = tile_entity.get_compound_tag_vec("Items") {
+            let mut found = BTreeMap::new();
+            count_items(&items[..], &query.items[..], &mut found);
+            for (k, v) in found {
+                if v >= query.min_count {
+                    info!("{} {} {} -> Found {} {} in {}", x, y, z, v, k, id);
+                }
+            }
+        }
+    }
+    if query.sign.is_some() || query.sign_regex.is_some() {
+        for line in ["Text1", "Text2", "Text3", "Text4"].iter().filter_map(|key| tile_entity.get_str(key).ok()) {
+            let text = sign_text(line);
+            let substring = query.sign.as_ref().map(|s| text.contains(s.as_str())).unwrap_or(false);
+            let regex = query.sign_regex.as_ref().map(|r| r.is_match(&text)).unwrap_or(false);
+            if substring || regex {
+                info!("{} {} {} -> Found sign \"{}\"", x, y, z, text);
+            }
+        }
+    }
+    if !query.spawners.is_empty() {
+        let mut mobs = vec![];
+        if let Ok(data) = tile_entity.get_compound_tag("SpawnData") {
+            mobs.extend(data.get_str("id").ok());
+        }
+        if let Ok(potentials) = tile_entity.get_compound_tag_vec("SpawnPotentials") {
+            for potential in potentials {
+                mobs.extend(potential.get_compound_tag("Entity").and_then(|e| e.get_str("id")).ok());
+            }
+        }
+        mobs.sort();
+        mobs.dedup();
+        for mob in mobs.into_iter().filter(|mob| query.spawners.iter().any(|s| s.as_str() == *mob)) {
+            info!("{} {} {} -> Found {} spawner", x, y, z, mob);
+        }
+    }
+    Some(())
+}
+
+/// Count matching items, looking inside the content of nested containers (shulker boxes in a chest)
+fn count_items<'a>(items: &[&CompoundTag], target: &'a [String], found: &mut BTreeMap<&'a String, i32>) {
+    for item in items {
+        if let Ok(id) = item.get_str("id") {
+            if let Some(target) = target.iter().find(|t| *t == id) {
+                *found.entry(target).or_insert(0) += item.get_i8("Count").unwrap_or(1) as i32;
+            }
+        }
+        if let Ok(nested) = item.get_compound_tag("tag")
+            .and_then(|tag| tag.get_compound_tag("BlockEntityTag"))
+            .and_then(|tag| tag.get_compound_tag_vec("Items")) {
+            count_items(&nested[..], target, found);
+        }
+    }
+}
+
+/// Sign lines are stored as JSON text components, flatten them to plain text
+fn sign_text(line: &str) -> String {
+    fn flatten(value: &serde_json::Value, out: &mut String) {
+        match value {
+            serde_json::Value::String(s) => out.push_str(s),
+            serde_json::Value::Array(array) => array.iter().for_each(|e| flatten(e, out)),
+            serde_json::Value::Object(map) => {
+                if let Some(text) = map.get("text") {
+                    flatten(text, out);
+                }
+                if let Some(extra) = map.get("extra") {
+                    flatten(extra, out);
+                }
+            },
+            _ => {},
+        }
+    }
+    match serde_json::from_str(line) {
+        Ok(value) => {
+            let mut text = String::new();
+            flatten(&value, &mut text);
+            text
+        },
+        Err(_) => line.to_string(),
+    }
+}
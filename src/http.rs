@@ -0,0 +1,296 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use serde_json::{json, Value};
+
+use crate::auth::Credentials;
+use crate::listen::{apply, is_timeout, record, ConnectionGuard, Server, CLIENT_HEADER, SHUTDOWN_POLL};
+use crate::protocol;
+
+/// Largest request line and headers
+const MAX_HEAD: usize = 16 * 1024;
+/// Headers parsed in a request
+const MAX_HEADERS: usize = 32;
+/// Largest request body
+const MAX_BODY: usize = 8 * 1024 * 1024;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Path without the query string
+    fn path(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    fn segments(&self) -> Vec<&str> {
+        self.path().trim_matches('/').split('/').collect()
+    }
+}
+
+/// A request which can't be served, answered with `status` and `{"error": message}`
+struct HttpError(u16, String);
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        417 => "Expectation Failed",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read more of the request, waiting for the client until the idle timeout or a shutdown
+fn read_more(server: &Server, stream: &mut TcpStream, buf: &mut Vec<u8>, started: Instant) -> Result<(), HttpError> {
+    let mut chunk = [0; 8192];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(HttpError(400, "Incomplete request".to_string())),
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                return Ok(());
+            },
+            Err(ref e) if is_timeout(e) => {
+                if server.shutdown.load(Ordering::SeqCst) || started.elapsed() >= server.options.idle_timeout {
+                    return Err(HttpError(408, "Request timeout".to_string()));
+                }
+            },
+            Err(e) => return Err(HttpError(400, e.to_string())),
+        }
+    }
+}
+
+/// Read the request line and headers, `body` holds whatever was read past them
+fn read_head(server: &Server, stream: &mut TcpStream, started: Instant) -> Result<HttpRequest, HttpError> {
+    let mut buf = Vec::new();
+    let (head_len, mut request) = loop {
+        read_more(server, stream, &mut buf, started)?;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => break (head_len, HttpRequest {
+                method: parsed.method.unwrap_or_default().to_string(),
+                path: parsed.path.unwrap_or_default().to_string(),
+                headers: parsed.headers.iter()
+                    .map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).into_owned()))
+                    .collect(),
+                body: vec![],
+            }),
+            Ok(httparse::Status::Partial) if buf.len() > MAX_HEAD => return Err(HttpError(431, "Headers too large".to_string())),
+            Ok(httparse::Status::Partial) => {},
+            Err(e) => return Err(HttpError(400, e.to_string())),
+        }
+    };
+    request.body = buf.split_off(head_len);
+    Ok(request)
+}
+
+/// Read the rest of the body, only once the client is known to be allowed to send it
+fn read_body(server: &Server, stream: &mut TcpStream, request: &mut HttpRequest, started: Instant) -> Result<(), HttpError> {
+    if request.header("transfer-encoding").is_some() {
+        return Err(HttpError(411, "Chunked bodies are not supported, send a Content-Length".to_string()));
+    }
+    let len = match request.header("content-length") {
+        Some(len) => len.trim().parse::<usize>().map_err(|_| HttpError(400, "Invalid Content-Length".to_string()))?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Err(HttpError(413, format!("Body larger than {} bytes, split the chunks in several requests", MAX_BODY)));
+    }
+    // Clients sending `Expect: 100-continue` (curl does above 1 KiB) wait for this before sending the body
+    match request.header("expect").map(str::trim) {
+        Some(expect) if !expect.eq_ignore_ascii_case("100-continue") => {
+            return Err(HttpError(417, format!("Unsupported expectation {}", expect)));
+        },
+        Some(_) if request.body.len() < len => {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .and_then(|_| stream.flush())
+                .map_err(|e| HttpError(400, e.to_string()))?;
+        },
+        _ => {},
+    }
+    while request.body.len() < len {
+        read_more(server, stream, &mut request.body, started)?;
+    }
+    request.body.truncate(len);
+    Ok(())
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason(status), body.len()
+    )?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+/// `POST /chunks`: a JSON dump or envelope, a JSON array of them, NDJSON or one binary dump or raw packet
+fn post_chunks(server: &Server, id: usize, peer: &str, request: &HttpRequest) -> Result<(u16, Value), HttpError> {
+    let content_type = request.header("content-type").and_then(|e| e.split(';').next()).unwrap_or_default().trim();
+    let starts_json = request.body.iter().find(|e| !e.is_ascii_whitespace()).map_or(false, |e| *e == b'{' || *e == b'[');
+    // Without a content type, JSON is told apart from binary dumps and raw packets by its first byte
+    let is_json = content_type == "application/json" || (content_type.is_empty() && starts_json);
+    let requests = match content_type {
+        "application/x-ndjson" | "application/jsonl" => String::from_utf8_lossy(&request.body)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                record(server, id, peer, line.as_bytes(), true);
+                protocol::open_text(line)
+            })
+            .collect(),
+        _ if is_json => {
+            let values = match serde_json::from_slice(&request.body) {
+                Ok(Value::Array(values)) => values,
+                Ok(value) => vec![value],
+                Err(e) => return Err(HttpError(400, format!("Invalid JSON: {}", e))),
+            };
+            values.into_iter()
+                .map(|value| {
+                    record(server, id, peer, value.to_string().as_bytes(), true);
+                    protocol::open_value(value)
+                })
+                .collect()
+        },
+        _ => {
+            record(server, id, peer, &request.body, false);
+            vec![protocol::open_binary_message(&request.body)]
+        },
+    };
+    let mut written = 0;
+    let mut retry = false;
+    let results: Vec<Value> = requests.into_iter()
+        .enumerate()
+        .map(|(index, request)| {
+            let result = apply(server, request.chunk);
+            match &result {
+                Ok(_) => written += 1,
+                Err(rejection) => retry |= rejection.retry(),
+            }
+            protocol::reply(request.reply_to.unwrap_or_else(|| json!(index)), &result, server.options.dry_run)
+        })
+        .collect();
+    let status = match written {
+        _ if written == results.len() => 200,
+        0 if retry => 503,
+        0 => 422,
+        _ => 207,
+    };
+    Ok((status, json!({"results": results})))
+}
+
+/// `GET /chunks/{x}/{z}`: whether the chunk is in the world and when this server imported it
+fn chunk_status(server: &Server, x: &str, z: &str) -> Result<(u16, Value), HttpError> {
+    let (x, z) = match (x.parse::<i32>(), z.parse::<i32>()) {
+        (Ok(x), Ok(z)) => (x, z),
+        _ => return Err(HttpError(400, "Invalid chunk coordinates".to_string())),
+    };
+    let status = server.writers.status(x, z).map_err(|e| HttpError(500, e.to_string()))?;
+    Ok((200, json!({
+        "x": x,
+        "z": z,
+        "exists": status.saved.is_some(),
+        "saved": status.saved,
+        "imported": status.imported.is_some(),
+        "time": status.imported.map(|(time, _)| time),
+        "result": status.imported.map(|(_, written)| written.name()),
+    })))
+}
+
+/// Check the credentials of the request headers, every endpoint but `GET /health` needs them
+fn authorize(server: &Server, peer: &mut String, request: &HttpRequest) -> Result<(), HttpError> {
+    if let ("GET", ["health"]) = (request.method.as_str(), request.segments().as_slice()) {
+        return Ok(());
+    }
+    let credentials = Credentials {
+        token: request.header("authorization").map(|e| e.trim_start_matches("Bearer ").to_string()),
+        client: request.header(CLIENT_HEADER).map(|e| e.to_string()),
+    };
+    if let Some(client) = &credentials.client {
+        *peer = format!("{} ({})", peer, client);
+    }
+    if server.options.auth.is_required() {
+        server.options.auth.check(&credentials).map_err(|e| HttpError(401, e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn route(server: &Server, id: usize, peer: &str, request: &HttpRequest) -> Result<(u16, Value), HttpError> {
+    let path = request.path();
+    match (request.method.as_str(), request.segments().as_slice()) {
+        ("GET", ["health"]) => {
+            let status = match server.shutdown.load(Ordering::SeqCst) {
+                true => "stopping",
+                false => "ok",
+            };
+            Ok((200, json!({"status": status, "connections": server.connections.load(Ordering::SeqCst), "dry_run": server.options.dry_run})))
+        },
+        ("POST", ["chunks"]) => post_chunks(server, id, peer, request),
+        ("GET", ["chunks", x, z]) => chunk_status(server, x, z),
+        (_, ["health"]) | (_, ["chunks"]) | (_, ["chunks", _, _]) => Err(HttpError(405, format!("{} not allowed on {}", request.method, path))),
+        _ => Err(HttpError(404, format!("No such endpoint {}", path))),
+    }
+}
+
+/// Answer a client over `--max-connections` with a 503 once its request head is read
+pub fn refuse_connection(server: Arc<Server>, mut stream: TcpStream, id: usize) {
+    let peer = stream.peer_addr().map(|e| e.to_string()).unwrap_or_else(|_| "?".to_string());
+    if let Err(e) = stream.set_read_timeout(Some(SHUTDOWN_POLL)) {
+        error!("#{} {}: {}", id, peer, e);
+        return;
+    }
+    // Whatever the request, the client is only told to come back later
+    let _ = read_head(&server, &mut stream, Instant::now());
+    if let Err(e) = respond(&mut stream, 503, &json!({"error": "Too many connections"})) {
+        warn!("#{} {}: Failed to respond: {}", id, peer, e);
+    }
+}
+
+/// Serve one HTTP request, the connection is closed after the response
+pub fn handle_connection(server: Arc<Server>, mut stream: TcpStream, id: usize) {
+    let _guard = ConnectionGuard(server.clone());
+    let mut peer = stream.peer_addr().map(|e| e.to_string()).unwrap_or_else(|_| "?".to_string());
+    if let Err(e) = stream.set_read_timeout(Some(SHUTDOWN_POLL)) {
+        error!("#{} {}: {}", id, peer, e);
+        return;
+    }
+    let started = Instant::now();
+    let (line, result) = match read_head(&server, &mut stream, started) {
+        Ok(mut request) => {
+            // Unauthorized clients are answered before their body is read
+            let result = authorize(&server, &mut peer, &request)
+                .and_then(|_| read_body(&server, &mut stream, &mut request, started))
+                .and_then(|_| route(&server, id, &peer, &request));
+            (format!("{} {}", request.method, request.path), result)
+        },
+        Err(e) => ("?".to_string(), Err(e)),
+    };
+    let (status, body) = result.unwrap_or_else(|HttpError(status, message)| (status, json!({"error": message})));
+    if let Err(e) = respond(&mut stream, status, &body) {
+        warn!("#{} {}: Failed to respond: {}", id, peer, e);
+    }
+    info!("#{} {}: {} {}", id, peer, line, status);
+}
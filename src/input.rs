@@ -0,0 +1,181 @@
+use std::{fs, io, io::{BufRead, Read}};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use crate::{binary, packet};
+
+/// Content of a dump and where it comes from
+pub struct RawDump {
+    /// Path of the dump file, `<archive>/<entry>` for a dump read from an archive
+    /// or `<file>:<line>` for a dump read from a newline-delimited JSON file
+    pub label: PathBuf,
+    pub data: Vec<u8>,
+    /// Whether the label is a dump file name carrying the chunk coordinates
+    pub named: bool,
+    /// Modification time of the dump in milliseconds
+    pub mtime: Option<u64>,
+}
+
+/// Position of a chunk, read without decoding the rest of the dump
+#[derive(Deserialize)]
+struct ChunkPos {
+    x: i32,
+    z: i32,
+}
+
+impl RawDump {
+    /// Chunk coordinates from the dump name (`x_z*.json`), or from its content when the name has none
+    pub fn position(&self) -> Option<(i32, i32)> {
+        match self.named {
+            true => dump_xz(&self.label),
+            false => None,
+        }.or_else(|| self.content_position())
+    }
+
+    /// Chunk coordinates from the content of the dump
+    fn content_position(&self) -> Option<(i32, i32)> {
+        if binary::is_binary(&self.data) {
+            return binary::read_position(&self.data);
+        }
+        if packet::is_packet(&self.data) {
+            return packet::read_position(&self.data);
+        }
+        serde_json::from_slice::<ChunkPos>(&self.data).ok().map(|pos| (pos.x, pos.z))
+    }
+}
+
+/// Chunk coordinates of a dump named `x_z[_anything].<json|d2m>[.gz|.zst]`
+pub fn dump_xz(path: &Path) -> Option<(i32, i32)> {
+    let name = path.file_name()?.to_str()?.split('.').next()?;
+    let mut name = name.split('_');
+    let x: i32 = name.next()?.parse().ok()?;
+    let z: i32 = name.next()?.parse().ok()?;
+    Some((x, z))
+}
+
+fn has_suffix(path: &Path, suffixes: &[&str]) -> bool {
+    path.file_name()
+        .and_then(|e| e.to_str())
+        .map(|name| name.to_lowercase())
+        .map(|name| suffixes.iter().any(|suffix| name.ends_with(suffix)))
+        .unwrap_or(false)
+}
+
+/// Archives holding many dumps, streamed entry by entry
+pub fn is_archive(path: &Path) -> bool {
+    has_suffix(path, &[".tar", ".tar.gz", ".tgz", ".zip"])
+}
+
+/// Newline-delimited JSON files holding one dump per line
+pub fn is_ndjson(path: &Path) -> bool {
+    has_suffix(path, &[".ndjson", ".jsonl", ".ndjson.gz", ".jsonl.gz", ".ndjson.zst", ".jsonl.zst"])
+}
+
+/// Uncompress the content of a `.gz` or `.zst` dump, other dumps are returned as is
+fn decompress(name: &Path, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    if has_suffix(name, &[".gz"]) {
+        GzDecoder::new(&data[..]).read_to_end(&mut out)?;
+    } else if has_suffix(name, &[".zst"]) {
+        zstd::stream::read::Decoder::new(&data[..])?.read_to_end(&mut out)?;
+    } else {
+        return Ok(data);
+    }
+    Ok(out)
+}
+
+/// Read a dump file, uncompressing it according to its extension
+pub fn read_dump<P: AsRef<Path>>(path: P) -> io::Result<RawDump> {
+    let path = path.as_ref();
+    let file = fs::File::open(path)?;
+    let mtime = file.metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64);
+    let mut data = Vec::new();
+    match () {
+        _ if has_suffix(path, &[".gz"]) => GzDecoder::new(file).read_to_end(&mut data)?,
+        _ if has_suffix(path, &[".zst"]) => zstd::stream::read::Decoder::new(file)?.read_to_end(&mut data)?,
+        _ => io::BufReader::new(file).read_to_end(&mut data)?,
+    };
+    Ok(RawDump {
+        label: path.to_path_buf(),
+        data,
+        named: true,
+        mtime,
+    })
+}
+
+/// Stream the dumps of an archive without extracting it, directories and non-dump entries are skipped.
+/// Entries which can't be read are passed to `f` as errors with their label
+pub fn for_each_entry<F: FnMut(Result<RawDump, (PathBuf, io::Error)>)>(archive: &Path, mut f: F) -> io::Result<()> {
+    let base = fs::canonicalize(archive).unwrap_or_else(|_| archive.to_path_buf());
+    let file = io::BufReader::new(fs::File::open(archive)?);
+    if has_suffix(archive, &[".zip"]) {
+        let mut zip = zip::ZipArchive::new(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if entry.is_dir() {
+                continue;
+            }
+            let label = base.join(entry.name());
+            let mut data = Vec::new();
+            let data = entry.read_to_end(&mut data).and_then(|_| decompress(&label, data));
+            f(data.map(|data| RawDump { label: label.clone(), data, named: true, mtime: None }).map_err(|e| (label, e)));
+        }
+    } else {
+        let reader: Box<dyn Read> = match has_suffix(archive, &[".tar.gz", ".tgz"]) {
+            true => Box::new(GzDecoder::new(file)),
+            false => Box::new(file),
+        };
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let label = base.join(entry.path()?);
+            let mtime = entry.header().mtime().ok().map(|mtime| mtime * 1000);
+            let mut data = Vec::new();
+            let data = entry.read_to_end(&mut data).and_then(|_| decompress(&label, data));
+            f(data.map(|data| RawDump { label: label.clone(), data, named: true, mtime }).map_err(|e| (label, e)));
+        }
+    }
+    Ok(())
+}
+
+/// Open a newline-delimited JSON file, uncompressing it according to its extension
+pub fn open_lines(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = fs::File::open(path)?;
+    Ok(match () {
+        _ if has_suffix(path, &[".gz"]) => Box::new(io::BufReader::new(GzDecoder::new(file))),
+        _ if has_suffix(path, &[".zst"]) => Box::new(io::BufReader::new(zstd::stream::read::Decoder::new(file)?)),
+        _ => Box::new(io::BufReader::new(file)),
+    })
+}
+
+/// Stream the dumps of a newline-delimited JSON reader one line at a time, labelled `<label>:<line>`.
+/// Blank lines are skipped
+pub fn for_each_line<R: BufRead, F: FnMut(RawDump)>(mut reader: R, label: &Path, mut f: F) -> io::Result<()> {
+    let base = fs::canonicalize(label).unwrap_or_else(|_| label.to_path_buf());
+    let mut line = 0;
+    loop {
+        let mut data = Vec::new();
+        if reader.read_until(b'\n', &mut data)? == 0 {
+            return Ok(());
+        }
+        line += 1;
+        if data.iter().all(|e| e.is_ascii_whitespace()) {
+            continue;
+        }
+        f(RawDump {
+            label: PathBuf::from(format!("{}:{}", base.display(), line)),
+            data,
+            named: false,
+            mtime: None,
+        });
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::{fs, io, io::{BufRead, BufReader, Write}};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const JOURNAL_NAME: &str = "dump-to-map.journal";
+
+/// Chunk of a dump and what `--pick` compares captures on, recorded so a resumed import
+/// still weighs new captures of the chunk against the one already imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capture {
+    pub x: i32,
+    pub z: i32,
+    /// Number of sections sent for the chunk
+    pub sections: u32,
+    pub timestamp: u64,
+}
+
+/// Dump files already written to a world, one `<hash>:<x>:<z>:<sections>:<timestamp> <path>` line per dump.
+/// Lines are only appended once the region file holding the chunk has been saved
+pub struct Journal {
+    entries: HashMap<(u64, PathBuf), Capture>,
+    file: Option<Mutex<fs::File>>,
+    /// Copy of the lines written by this run, so undoing the run can remove them again
+    session: Option<Mutex<fs::File>>,
+}
+
+/// Hash and capture of a line, `None` when the line is malformed
+fn parse_key(key: &str) -> Option<(u64, Capture)> {
+    let fields: Vec<&str> = key.split(':').collect();
+    match fields.as_slice() {
+        [hash, x, z, sections, timestamp] => Some((u64::from_str_radix(hash, 16).ok()?, Capture {
+            x: x.parse().ok()?,
+            z: z.parse().ok()?,
+            sections: sections.parse().ok()?,
+            timestamp: timestamp.parse().ok()?,
+        })),
+        _ => None,
+    }
+}
+
+impl Journal {
+    /// Open the journal of a world, `resume` loads the dumps recorded by previous runs.
+    /// A journal which is not `writable` is only read and never created
+    pub fn open<P: AsRef<Path>>(world: P, resume: bool, writable: bool) -> io::Result<Journal> {
+        let path = world.as_ref().join(JOURNAL_NAME);
+        let mut entries = HashMap::new();
+        if resume {
+            match fs::File::open(&path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        let line = line?;
+                        let mut sp = line.splitn(2, ' ');
+                        match (sp.next().and_then(parse_key), sp.next()) {
+                            (Some((hash, capture)), Some(path)) => {
+                                entries.insert((hash, PathBuf::from(path)), capture);
+                            },
+                            // Interrupted while writing the last line
+                            _ => warn!("{}: ignoring malformed line {:?}", JOURNAL_NAME, line),
+                        }
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        let file = match writable {
+            true => Some(Mutex::new(fs::OpenOptions::new().create(true).append(true).open(&path)?)),
+            false => None,
+        };
+        Ok(Journal {
+            entries,
+            file,
+            session: None,
+        })
+    }
+
+    /// Also write the lines of this run to `path`, read back by `forget` when the run is undone
+    pub fn with_session<P: AsRef<Path>>(mut self, path: P) -> io::Result<Journal> {
+        if self.file.is_some() {
+            self.session = Some(Mutex::new(fs::OpenOptions::new().create(true).append(true).open(path)?));
+        }
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Capture of a dump already imported, `None` when it was not imported yet
+    pub fn get(&self, path: &Path, hash: u64) -> Option<Capture> {
+        self.entries.get(&(hash, journal_path(path))).cloned()
+    }
+
+    /// Record dumps whose chunks are now on disk
+    pub fn record(&self, dumps: &[(PathBuf, u64, Capture)]) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) if !dumps.is_empty() => file,
+            _ => return Ok(()),
+        };
+        let mut lines = String::new();
+        for (path, hash, capture) in dumps {
+            lines.push_str(&format!(
+                "{:016x}:{}:{}:{}:{} {}\n",
+                hash, capture.x, capture.z, capture.sections, capture.timestamp, journal_path(path).display(),
+            ));
+        }
+        // The session copy goes first, a line it misses would survive an undo
+        if let Some(session) = &self.session {
+            let mut session = session.lock().unwrap();
+            session.write_all(lines.as_bytes())?;
+            session.sync_data()?;
+        }
+        let mut file = file.lock().unwrap();
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// Remove the lines written by an undone run (its `session` copy) from the journal of a world,
+/// returning the number of removed lines. A dump recorded by several runs keeps its other lines
+pub fn forget<P: AsRef<Path>, S: AsRef<Path>>(world: P, session: S) -> io::Result<usize> {
+    let read = |path: &Path| match fs::read_to_string(path) {
+        Ok(lines) => Ok(Some(lines)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let path = world.as_ref().join(JOURNAL_NAME);
+    let (session, journal) = match (read(session.as_ref())?, read(&path)?) {
+        (Some(session), Some(journal)) => (session, journal),
+        _ => return Ok(0),
+    };
+    let mut forgotten: HashMap<&str, usize> = HashMap::new();
+    for line in session.lines() {
+        *forgotten.entry(line).or_insert(0) += 1;
+    }
+    let mut removed = 0;
+    // The lines of the undone run are the latest ones
+    let mut kept: Vec<&str> = journal.lines().rev()
+        .filter(|line| match forgotten.get_mut(line) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                removed += 1;
+                false
+            },
+            _ => true,
+        })
+        .collect();
+    kept.reverse();
+    let tmp = path.with_extension("journal.tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        for line in kept {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, &path)?;
+    Ok(removed)
+}
+
+/// Absolute path of a dump, as recorded
+fn journal_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// FNV-1a hash of a dump content, enough to notice a dump was replaced since it was imported
+pub fn content_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forget_session_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let session = world.join("session.journal");
+        let (a, b) = (Path::new("/dumps/0_0.json"), Path::new("/dumps/0_1.json"));
+        let capture = Capture { x: 0, z: 0, sections: 16, timestamp: 1 };
+        let earlier = Journal::open(world, false, true).unwrap();
+        earlier.record(&[(a.to_path_buf(), 1, capture)]).unwrap();
+        let undone = Journal::open(world, true, true).unwrap().with_session(&session).unwrap();
+        // Recorded again by the undone run after a --restart
+        undone.record(&[(a.to_path_buf(), 1, capture), (b.to_path_buf(), 2, capture)]).unwrap();
+
+        assert_eq!(forget(world, &session).unwrap(), 2);
+        let journal = Journal::open(world, true, false).unwrap();
+        assert_eq!(journal.get(a, 1), Some(capture));
+        assert_eq!(journal.get(b, 2), None);
+        assert_eq!(forget(world, world.join("missing.journal")).unwrap(), 0);
+    }
+
+    #[test]
+    fn lines() {
+        let capture = Capture { x: -1, z: 33, sections: 7, timestamp: 1589000000000 };
+        assert_eq!(parse_key("00000000000000ff:-1:33:7:1589000000000"), Some((255, capture)));
+        assert_eq!(parse_key("00000000000000ff"), None);
+        assert_eq!(parse_key("00000000000000ff:-1:33"), None);
+        assert_eq!(parse_key("nope"), None);
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::{io, thread};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+use tungstenite::protocol::frame::{coding::CloseCode, CloseFrame};
+use serde_json::json;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::{HeaderValue, Response as HttpResponse, StatusCode};
+use tungstenite::server::{accept, accept_hdr};
+
+use crate::auth::{Auth, Credentials};
+use crate::backup::Backup;
+use crate::conflict::ConflictPolicy;
+use crate::http;
+use crate::models::PacketChunk;
+use crate::protocol::{self, Rejection};
+use crate::record::Recorder;
+use crate::writer::{RegionWriters, Written};
+
+/// Handshake header naming the client, checked against the allow-list
+pub(crate) const CLIENT_HEADER: &str = "x-client-name";
+/// How often the accept loop checks for a shutdown, and the read timeout of the connections
+/// so they notice a shutdown or an idle client
+pub(crate) const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+/// Time given to a client to complete the websocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time given to a client to send its credentials once connected, when they were not in the handshake
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options of the `listen` subcommand
+pub struct ListenOptions {
+    /// Address and port to listen on
+    pub bind: String,
+    /// Connections served at the same time, the others are refused
+    pub max_connections: usize,
+    /// Number of region writers
+    pub jobs: usize,
+    /// Connections without any message for this long are closed
+    pub idle_timeout: Duration,
+    /// What to do with chunks already in the world
+    pub on_conflict: ConflictPolicy,
+    /// Go through every chunk without writing anything
+    pub dry_run: bool,
+    /// Save the chunks overwritten by the session so it can be undone
+    pub backup: bool,
+    /// Credentials required from the clients
+    pub auth: Auth,
+    /// Where the received messages are recorded
+    pub record: Option<Recorder>,
+    /// Address and port of the HTTP API, not served when `None`
+    pub http_bind: Option<String>,
+}
+
+/// State shared by the connections
+pub(crate) struct Server {
+    pub(crate) options: ListenOptions,
+    pub(crate) writers: RegionWriters,
+    pub(crate) shutdown: Arc<AtomicBool>,
+    pub(crate) connections: AtomicUsize,
+}
+
+/// Counts a connection for as long as it is alive
+pub(crate) struct ConnectionGuard(pub(crate) Arc<Server>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counters logged when a connection ends
+#[derive(Default)]
+struct Session {
+    messages: usize,
+    patched: usize,
+    kept: usize,
+    invalid: usize,
+    failed: usize,
+}
+
+impl Session {
+    fn record(&mut self, result: &Result<(i32, i32, Written), Rejection>) {
+        self.messages += 1;
+        match result {
+            Ok((_, _, Written::Kept)) => self.kept += 1,
+            Ok(_) => self.patched += 1,
+            Err(rejection) if rejection.is_invalid() => self.invalid += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+}
+
+/// Decode a chunk and hand it to the writer of its region
+pub(crate) fn apply(server: &Server, chunk: Result<PacketChunk, Rejection>) -> Result<(i32, i32, Written), Rejection> {
+    let chunk = chunk?;
+    let chunk_x = chunk.x;
+    let chunk_z = chunk.z;
+    let chunk = chunk.into_compound_tag().map_err(Rejection::decode)?;
+    let written = server.writers.write(chunk_x, chunk_z, chunk)?;
+    Ok((chunk_x, chunk_z, written))
+}
+
+/// Record a message when recording is on
+pub(crate) fn record(server: &Server, id: usize, peer: &str, data: &[u8], text: bool) {
+    if let Some(recorder) = &server.options.record {
+        if let Err(e) = recorder.record(id, peer, data, text) {
+            error!("#{} {}: Failed to record message: {}", id, peer, e);
+        }
+    }
+}
+
+/// `host:port`, with an IPv6 host in brackets
+pub fn address(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Whether `host` only accepts local connections, host names are resolved
+pub fn is_loopback(host: &str) -> bool {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => match (host, 0).to_socket_addrs() {
+            Ok(addrs) => {
+                let addrs: Vec<_> = addrs.collect();
+                !addrs.is_empty() && addrs.iter().all(|addr| addr.ip().is_loopback())
+            },
+            Err(_) => false,
+        },
+    }
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Serves a connection, or refuses it when `--max-connections` is reached
+type Handler = fn(Arc<Server>, TcpStream, usize);
+
+/// Tell a websocket client over `--max-connections` to come back later
+fn refuse_connection(_server: Arc<Server>, stream: TcpStream, id: usize) {
+    let peer = stream.peer_addr().map(|e| e.to_string()).unwrap_or_else(|_| "?".to_string());
+    if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+        error!("#{} {}: {}", id, peer, e);
+        return;
+    }
+    match accept(stream) {
+        Ok(mut websocket) => {
+            let _ = websocket.close(Some(CloseFrame { code: CloseCode::Again, reason: "Too many connections".into() }));
+            let _ = websocket.write_pending();
+        },
+        Err(e) => warn!("#{} {}: Websocket handshake failed: {}", id, peer, e),
+    }
+}
+
+fn handle_connection(server: Arc<Server>, stream: TcpStream, id: usize) {
+    let _guard = ConnectionGuard(server.clone());
+    let peer = stream.peer_addr().map(|e| e.to_string()).unwrap_or_else(|_| "?".to_string());
+    // A short timeout would interrupt the handshake of a slow client
+    if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+        error!("#{} {}: {}", id, peer, e);
+        return;
+    }
+    let auth = &server.options.auth;
+    let mut authenticated = !auth.is_required();
+    let mut client = None;
+    // Credentials given as headers are checked during the handshake, otherwise in the first message.
+    // The (large) error type is imposed by tungstenite's callback
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        let header = |name: &str| request.headers().get(name).and_then(|e: &HeaderValue| e.to_str().ok());
+        let credentials = Credentials {
+            token: header("authorization").map(|e| e.trim_start_matches("Bearer ").to_string()),
+            client: header(CLIENT_HEADER).map(|e| e.to_string()),
+        };
+        client = credentials.client.clone();
+        if authenticated || (credentials.token.is_none() && credentials.client.is_none()) {
+            return Ok(response);
+        }
+        match auth.check(&credentials) {
+            Ok(_) => {
+                authenticated = true;
+                Ok(response)
+            },
+            Err(e) => Err(HttpResponse::builder().status(StatusCode::UNAUTHORIZED).body(Some(e.to_string())).unwrap()),
+        }
+    };
+    let mut websocket = match accept_hdr(stream, callback) {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!("#{} {}: Websocket handshake failed: {}", id, peer, e);
+            return;
+        },
+    };
+    if let Err(e) = websocket.get_ref().set_read_timeout(Some(SHUTDOWN_POLL)) {
+        error!("#{} {}: {}", id, peer, e);
+        return;
+    }
+    let mut peer = match client {
+        Some(client) => format!("{} ({})", peer, client),
+        None => peer,
+    };
+    info!("#{} {}: Connected", id, peer);
+    let started = Instant::now();
+    let mut last_message = Instant::now();
+    let mut session = Session::default();
+    let reason = loop {
+        if server.shutdown.load(Ordering::SeqCst) {
+            let _ = websocket.close(Some(CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() }));
+            break "server shutting down".to_string();
+        }
+        if !authenticated && started.elapsed() >= AUTH_TIMEOUT {
+            let _ = websocket.close(Some(CloseFrame { code: CloseCode::Policy, reason: "Authentication timeout".into() }));
+            break "authentication timeout".to_string();
+        }
+        let msg = match websocket.read_message() {
+            Ok(msg) => msg,
+            Err(tungstenite::Error::Io(ref e)) if is_timeout(e) => {
+                if last_message.elapsed() >= server.options.idle_timeout {
+                    let _ = websocket.close(Some(CloseFrame { code: CloseCode::Away, reason: "Idle timeout".into() }));
+                    break "idle timeout".to_string();
+                }
+                // Sends the pongs queued by the reads
+                if let Err(e) = websocket.write_pending() {
+                    if !matches!(e, tungstenite::Error::Io(ref e) if is_timeout(e)) {
+                        break format!("error: {}", e);
+                    }
+                }
+                continue;
+            },
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => break "closed".to_string(),
+            Err(e) => break format!("error: {}", e),
+        };
+        // Only authenticated clients are kept alive by their messages
+        if authenticated {
+            last_message = Instant::now();
+        }
+        if !authenticated && (msg.is_text() || msg.is_binary()) {
+            // Nothing from an unauthenticated client is decoded but its credentials
+            let credentials = match &msg {
+                Message::Text(data) => serde_json::from_str::<Credentials>(data).map_err(|_| "Expected credentials"),
+                _ => Err("Expected credentials"),
+            };
+            match credentials.and_then(|credentials| auth.check(&credentials).map(|_| credentials.client)) {
+                Ok(client) => {
+                    authenticated = true;
+                    last_message = Instant::now();
+                    if let Some(client) = client {
+                        peer = format!("{} ({})", peer, client);
+                    }
+                    info!("#{} {}: Authenticated", id, peer);
+                    if let Err(e) = websocket.write_message(Message::Text(json!({"auth": "ok"}).to_string())) {
+                        break format!("error: {}", e);
+                    }
+                    continue;
+                },
+                Err(e) => {
+                    let _ = websocket.close(Some(CloseFrame { code: CloseCode::Policy, reason: e.into() }));
+                    break format!("unauthorized: {}", e);
+                },
+            }
+        }
+        match &msg {
+            Message::Text(data) => record(&server, id, &peer, data.as_bytes(), true),
+            Message::Binary(data) => record(&server, id, &peer, data, false),
+            _ => {},
+        }
+        let request = match msg {
+            Message::Binary(data) => protocol::open_binary_message(&data),
+            Message::Text(data) => protocol::open_text(&data),
+            // Pings are answered by tungstenite itself
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(frame) => {
+                debug!("#{} {}: Close frame {:?}", id, peer, frame);
+                // Waits for tungstenite to send the close reply
+                let _ = websocket.write_pending();
+                break "closed by the client".to_string();
+            },
+        };
+        let result = apply(&server, request.chunk);
+        if let Some(rejection) = result.as_ref().err().filter(|e| e.is_invalid()) {
+            warn!("#{} {}: Invalide packet received: {}", id, peer, rejection.message);
+        }
+        session.record(&result);
+        if let Some(reply_to) = request.reply_to {
+            let reply = protocol::reply(reply_to, &result, server.options.dry_run);
+            if let Err(e) = websocket.write_message(Message::Text(reply.to_string())) {
+                break format!("error: {}", e);
+            }
+        }
+    };
+    let _ = websocket.write_pending();
+    info!(
+        "#{} {}: Disconnected ({}) after {:.0?}, {} messages: {} patched, {} kept, {} invalid, {} failed",
+        id, peer, reason, started.elapsed(), session.messages, session.patched, session.kept, session.invalid, session.failed
+    );
+}
+
+/// Serve websocket (and HTTP) clients until Ctrl-C (or SIGTERM): accepting stops, every connection finishes
+/// the chunk being written and the backup is closed before returning
+pub fn run(output: &str, options: ListenOptions) -> io::Result<()> {
+    let backup = match options.dry_run || !options.backup {
+        true => None,
+        false => Some(Arc::new(Backup::create(output)?)),
+    };
+    let writers = RegionWriters::start(output, options.jobs, options.on_conflict, options.dry_run, backup.clone());
+    let listener = TcpListener::bind(&options.bind)?;
+    listener.set_nonblocking(true)?;
+    info!("Listening on {} ...", options.bind);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = Arc::new(Server {
+        options,
+        writers,
+        shutdown: shutdown.clone(),
+        connections: AtomicUsize::new(0),
+    });
+    ctrlc::set_handler(move || {
+        if shutdown.swap(true, Ordering::SeqCst) {
+            // Second signal, don't wait for the connections
+            std::process::exit(130);
+        }
+        eprintln!("Shutting down, waiting for the connections to finish ...");
+    }).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut listeners = vec![(listener, handle_connection as Handler, refuse_connection as Handler)];
+    if let Some(http_bind) = &server.options.http_bind {
+        let listener = TcpListener::bind(http_bind)?;
+        listener.set_nonblocking(true)?;
+        info!("Serving HTTP on {} ...", http_bind);
+        listeners.push((listener, http::handle_connection, http::refuse_connection));
+    }
+    let mut next_id = 0;
+    // Connection threads by id, each one sends its id once done
+    let mut threads = HashMap::new();
+    let (done_tx, done_rx) = mpsc::channel();
+    while !server.shutdown.load(Ordering::SeqCst) {
+        let mut idle = true;
+        for (listener, handler, refuse) in &listeners {
+            let (stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                },
+            };
+            idle = false;
+            if let Err(e) = stream.set_nonblocking(false) {
+                warn!("{}: {}", peer, e);
+                continue;
+            }
+            next_id += 1;
+            let handler = match server.connections.load(Ordering::SeqCst) >= server.options.max_connections {
+                true => {
+                    warn!("#{} {}: Refused, {} connections already", next_id, peer, server.options.max_connections);
+                    *refuse
+                },
+                false => {
+                    server.connections.fetch_add(1, Ordering::SeqCst);
+                    *handler
+                },
+            };
+            let (server, id, done) = (server.clone(), next_id, done_tx.clone());
+            threads.insert(id, thread::spawn(move || {
+                handler(server, stream, id);
+                let _ = done.send(id);
+            }));
+        }
+        for id in done_rx.try_iter() {
+            if let Some(thread) = threads.remove(&id) {
+                let _ = thread.join();
+            }
+        }
+        if idle {
+            thread::sleep(SHUTDOWN_POLL);
+        }
+    }
+    for (_, thread) in threads {
+        let _ = thread.join();
+    }
+    let server = Arc::try_unwrap(server).unwrap_or_else(|_| unreachable!("Connections are done"));
+    server.writers.stop();
+    if let Some(backup) = backup.and_then(|backup| Arc::try_unwrap(backup).ok()) {
+        backup.finish()?;
+    }
+    info!("Stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address("127.0.0.1", 4242), "127.0.0.1:4242");
+        assert_eq!(address("::1", 4242), "[::1]:4242");
+        assert_eq!(address("localhost", 4242), "localhost:4242");
+    }
+
+    #[test]
+    fn loopback() {
+        assert!(is_loopback("127.0.0.1"));
+        assert!(is_loopback("::1"));
+        assert!(is_loopback("localhost"));
+        assert!(!is_loopback("0.0.0.0"));
+        assert!(!is_loopback("192.168.1.2"));
+        assert!(!is_loopback("::"));
+    }
+}
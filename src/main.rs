@@ -5,172 +5,93 @@ extern crate pretty_env_logger;
 #[macro_use] extern crate log;
 extern crate serde_json;
 extern crate serde;
-use std::{io::Read, fs, path::PathBuf};
-use anvil_region::AnvilChunkProvider;
-use clap::{Arg, App, SubCommand};
-use std::collections::{BTreeMap, btree_map, VecDeque};
-use std::path::Path;
-use std::io;
-use std::thread;
-use std::net::TcpListener;
-use std::thread::spawn;
-use tungstenite::server::accept;
+use std::{fs, path::PathBuf, str::FromStr, time::Duration};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use regex::Regex;
 
 mod models;
+mod region;
+mod bulk;
+mod find;
+mod stats;
+mod progress;
+mod journal;
+mod conflict;
+mod backup;
+mod input;
+mod binary;
+mod nbt_json;
+mod packet;
+mod convert;
+mod capture;
+mod listen;
+mod auth;
+mod writer;
+mod protocol;
+mod record;
+mod http;
 
-use models::*;
+use region::RegionFile;
+use bulk::{get_chunks_fmap, run, BulkOptions, PICKS};
+use conflict::CONFLICT_POLICIES;
+use auth::Auth;
+use listen::ListenOptions;
+use record::Recorder;
+use find::{ChunkSource, FindActor, FindQuery, FindRequest};
+use stats::{BlockStats, StatsActor, StatsRequest};
 
-pub fn region_path_from(mut path: PathBuf, x: i32, z: i32) -> PathBuf {
-    path.push(format!("r.{}.{}.mca", x, z));
-    path
+fn on_conflict_arg() -> Arg<'static, 'static> {
+    Arg::with_name("on-conflict")
+        .help("What to do when a chunk already exists in the world")
+        .long("on-conflict")
+        .possible_values(&CONFLICT_POLICIES)
+        .default_value("overwrite")
+        .takes_value(true)
 }
 
-fn fname_xz(fname: &str) -> Option<(i32, i32)> {
-    let mut fname = fname.split('_');
-    let x: i32 = fname.next()?.parse().ok()?;
-    let y: i32 = fname.next()?.parse().ok()?;
-    Some((x, y))
+fn dry_run_arg() -> Arg<'static, 'static> {
+    Arg::with_name("dry-run")
+        .help("Decode everything and report what would change without writing to the world")
+        .long("dry-run")
 }
 
-fn get_chunks_fmap<T: AsRef<Path>>(dir: T) -> io::Result<Vec<Vec<PathBuf>>> {
-    let mut ret: BTreeMap<i32, BTreeMap<i32, Vec<PathBuf>>> = BTreeMap::new();
-    for entry in fs::read_dir(dir)? {
-        if let Some(path) = entry.ok().and_then(|e| Some(e.path())) {
-            let fname = path.file_stem().unwrap().to_str().unwrap();
-            if let Some((x, z)) = fname_xz(fname) {
-                match ret.entry(x / 32) {
-                    btree_map::Entry::Occupied(mut e) => {
-                        let ent = e.get_mut();
-                        if let Some(ent) = ent.get_mut(&(z / 32)) {
-                            ent.push(path);
-                        } else {
-                            ent.insert(z / 32, vec![path]);
-                        }
-                    },
-                    btree_map::Entry::Vacant(e) => {
-                        let mut new = BTreeMap::new();
-                        new.insert(z / 32, vec![path]);
-                        e.insert(new);
-                    }
-                }
-            }
-        }
-    }
-    let mut total = vec![];
-    for (_x, item) in ret {
-        for (_z, item) in item {
-            total.push(item);
-        }
-    }
-    Ok(total)
-}
-
-struct WorkHandler {
-    payload: VecDeque<PathBuf>,
-    buffer: String,
-}
-
-impl WorkHandler {
-
-    pub fn new(payload: VecDeque<PathBuf>) -> Self {
-        Self {
-            payload,
-            buffer: String::new(),
-        }
-    }
-
-    pub fn next(&mut self, chunk_provider: &mut AnvilChunkProvider<'_>) -> Option<()> {
-        let path = self.payload.pop_back()?;
-        let mut file= std::fs::OpenOptions::new().read(true).open(&path).ok()?;
-        self.buffer.clear();
-        file.read_to_string(&mut self.buffer).ok()?;
-        let chunk: PacketChunk = serde_json::from_str(&self.buffer).ok()?;
-        let chunk_x = chunk.x;
-        let chunk_z = chunk.z;
-        let chunk = chunk.into();
-        match chunk_provider.save_chunk(chunk_x, chunk_z, chunk) {
-            Ok(_) =>{},// info!("{}:{} Patched !", chunk_x, chunk_z),
-            Err(e) => error!("{}:{} Failed to patch: {:?}", chunk_x, chunk_z, e),
-        }
-        Some(())
-    }
-
-    pub fn spawn(chunks: Vec<Vec<PathBuf>>, folder: PathBuf) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            let mut provider =  AnvilChunkProvider::new(folder.to_str().unwrap());
-            let mut w = WorkHandler::new(chunks.into_iter().flatten().collect());
-            while let Some(_) = w.next(&mut provider) {};
-        })
-    }
+fn no_backup_arg() -> Arg<'static, 'static> {
+    Arg::with_name("no-backup")
+        .help("Don't save the overwritten chunks, the import can't be undone")
+        .long("no-backup")
 }
 
-const NBR_THREAD: usize = 16;
-
-fn run(output: &str, patch: &str) -> std::io::Result<()> {
-    let output = PathBuf::from(output);
-    let patch = PathBuf::from(patch);
-    let mut chunks = get_chunks_fmap(&patch)?;
-    let nbr_chunk_per_thread = chunks.len() / NBR_THREAD;
-    let mut join = Vec::with_capacity(NBR_THREAD);
-    for _ in (0..(NBR_THREAD - 1)).into_iter() {
-        if chunks.len() > nbr_chunk_per_thread {
-            let h = WorkHandler::spawn(chunks.drain(0 .. nbr_chunk_per_thread).collect(), output.clone());
-            join.push(h);
-        } else {
-            break;
-        }
-    }
-    join.push(WorkHandler::spawn(chunks, output.clone()));
-    let mut cptr = join.len();
-    for join in join.into_iter() {
-        let _ = join.join();
-        cptr -= 1;
-        info!("{} worker remaning ...", cptr);
-    }
-
-    Ok(())
+/// Validator of the options taking a number
+fn number<T: FromStr>(value: String) -> Result<(), String> where T::Err: std::fmt::Display {
+    value.parse::<T>().map(|_| ()).map_err(|e| format!("{:?} is not a valid number: {}", value, e))
 }
 
-struct FindActor {
-    target: Vec<String>,
-    path: String,
+/// Value of an option checked by `number`
+fn parsed<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|e| e.parse().ok())
 }
 
-impl FindActor {
-    fn new(target: Vec<String>, path: String) -> Self {
-        Self {
-            target,
-            path,
-        }
-    }
+/// Exit with a usage error, `subcommand` needs the world directory given with `-o`
+fn missing_output(subcommand: &str, usage: &str) -> ! {
+    clap::Error::with_description(
+        &format!("The following required arguments were not provided:\n    -o <output>\n\n{} needs the minecraft region directory\n\n{}\n\nFor more information try --help", subcommand, usage),
+        clap::ErrorKind::MissingRequiredArgument,
+    ).exit()
 }
 
-#[derive(Message, Debug)]
-#[rtype(result = "()")]
-struct FindRequest(RegionFile);
-
-impl Handler<FindRequest> for FindActor {
-    type Result = ();
-
-    fn handle(&mut self, msg: FindRequest, ctx: &mut SyncContext<Self>) {
-        let provider =  AnvilChunkProvider::new(&self.path);
-        let region = msg.0;
-        for cx in (0..32).into_iter().map(|cx| cx + (region.x * 32)) {
-            for cz in (0..32).into_iter().map(|cz| cz + (region.z * 32)) {
-                if let Ok(chunk) = provider.load_chunk(cx, cz) {
-                    find(cx * 16, cz * 16,chunk, &self.target[..]);
-                }
-            }
-        }
+/// Region files of the world, or groups of JSON dumps when scanning a dump directory
+fn chunk_sources(world: &str, dump: Option<&str>) -> Vec<ChunkSource> {
+    match dump {
+        Some(dump) => get_chunks_fmap(dump).expect("Wrong dump directory").into_iter().map(|job| ChunkSource::Dump(job.paths)).collect(),
+        None => fs::read_dir(world)
+            .expect("Wrong map directory")
+            .filter_map(|e| e.ok())
+            .filter_map(|e| RegionFile::new(e.path()))
+            .map(ChunkSource::World)
+            .collect(),
     }
 }
 
-impl actix::Actor for FindActor {
-    type Context = SyncContext<Self>;
-}
-
-
-
 #[actix_rt::main]
 async fn main() {
     if std::env::var("PALETTE").is_err() {
@@ -182,7 +103,6 @@ async fn main() {
         Arg::with_name("output")
             .help("Minecraft region directory to update")
             .short("o")
-            .required(true)
             .takes_value(true)
     )
     .subcommand(
@@ -190,16 +110,47 @@ async fn main() {
                 .about("Copy a bunch of json chunk sections into an existing minecraft world")
                 .arg(
                     Arg::with_name("patch")
-                        .help("A directory containing JOSN chunk regions")
+                        .help("A directory containing JOSN chunk regions, an archive or NDJSON file of them, or - to read NDJSON from stdin")
                         .short("p")
                         .long("patch")
                         .required(true)
                         .takes_value(true)
                 )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of worker threads [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("restart")
+                        .help("Import every dump again, ignoring the ones recorded in the journal")
+                        .long("restart")
+                )
+                .arg(
+                    Arg::with_name("quarantine")
+                        .help("Move the dumps which can't be read or decoded to this directory")
+                        .short("q")
+                        .long("quarantine")
+                        .takes_value(true)
+                )
+                .arg(on_conflict_arg())
+                .arg(dry_run_arg())
+                .arg(no_backup_arg())
+                .arg(
+                    Arg::with_name("pick")
+                        .help("Which capture to keep when several dumps target the same chunk")
+                        .long("pick")
+                        .possible_values(&PICKS)
+                        .default_value("latest")
+                        .takes_value(true)
+                )
         )
         .subcommand(
             SubCommand::with_name("listen")
-                .about("Listen for chunk sections over a websocket and apply them to an existing minecraft world")
+                .about("Listen for chunk sections over a websocket (and HTTP) and apply them to an existing minecraft world")
                 .arg(
                     Arg::with_name("port")
                         .help("Listen port")
@@ -207,7 +158,90 @@ async fn main() {
                         .short("p")
                         .long("port")
                         .takes_value(true)
+                        .validator(number::<u16>)
+                )
+                .arg(
+                    Arg::with_name("bind")
+                        .help("Address to listen on, 0.0.0.0 for every interface")
+                        .default_value("127.0.0.1")
+                        .short("b")
+                        .long("bind")
+                        .takes_value(true)
                 )
+                .arg(
+                    Arg::with_name("max-connections")
+                        .help("Connections served at the same time, the others are refused")
+                        .default_value("16")
+                        .long("max-connections")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of region writers [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("idle-timeout")
+                        .help("Seconds without any message before a connection is closed")
+                        .default_value("300")
+                        .long("idle-timeout")
+                        .takes_value(true)
+                        .validator(number::<u64>)
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .help("Token the clients have to present")
+                        .long("token")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("allow-client")
+                        .help("Client name allowed to connect")
+                        .long("allow-client")
+                        .multiple(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("auth-file")
+                        .help("JSON file with the token and the allowed client names")
+                        .long("auth-file")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("http-port")
+                        .help("Also serve the HTTP API on this port, on the same address")
+                        .long("http-port")
+                        .takes_value(true)
+                        .validator(number::<u16>)
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .help("Directory where every received message is recorded, to be replayed later")
+                        .long("record")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("record-max-size")
+                        .help("Size of a recording file in MB, a new file is started past it")
+                        .default_value("64")
+                        .long("record-max-size")
+                        .takes_value(true)
+                        .validator(number::<u64>)
+                )
+                .arg(
+                    Arg::with_name("record-keep")
+                        .help("Recording files kept, the oldest are removed [default: all of them]")
+                        .long("record-keep")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(on_conflict_arg())
+                .arg(dry_run_arg())
+                .arg(no_backup_arg())
         )
         .subcommand(
             SubCommand::with_name("find")
@@ -233,48 +267,277 @@ async fn main() {
                         .short("f")
                         .long("force")
                 )
+                .arg(
+                    Arg::with_name("item")
+                        .help("Item id to find in containers (chests, shulker boxes, barrels ...)")
+                        .short("i")
+                        .long("item")
+                        .multiple(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("min-count")
+                        .help("Minimum number of matching items in a container")
+                        .long("min-count")
+                        .default_value("1")
+                        .takes_value(true)
+                        .validator(number::<i32>)
+                )
+                .arg(
+                    Arg::with_name("sign")
+                        .help("Text to find on signs")
+                        .long("sign")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("sign-regex")
+                        .help("Regex to match against sign lines")
+                        .long("sign-regex")
+                        .takes_value(true)
+                        .validator(|e| Regex::new(&e).map(|_| ()).map_err(|e| e.to_string()))
+                )
+                .arg(
+                    Arg::with_name("spawner")
+                        .help("Mob type to find in spawners")
+                        .long("spawner")
+                        .multiple(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("dump")
+                        .help("Read a directory of JSON chunk dumps instead of the world")
+                        .short("d")
+                        .long("dump")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of worker threads [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Count blocks of a world")
+                .arg(
+                    Arg::with_name("block")
+                        .help("Only count these blocks")
+                        .short("b")
+                        .long("block")
+                        .multiple(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .help("Output format")
+                        .long("format")
+                        .possible_values(&["table", "json"])
+                        .default_value("table")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("levels")
+                        .help("Print the per y level distribution in table output")
+                        .long("levels")
+                )
+                .arg(
+                    Arg::with_name("dump")
+                        .help("Read a directory of JSON chunk dumps instead of the world")
+                        .short("d")
+                        .long("dump")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of worker threads [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-capture")
+                .about("Replay the chunks of a recorded packet capture into an existing minecraft world")
+                .arg(
+                    Arg::with_name("capture")
+                        .help("Capture file of length-prefixed play state packets")
+                        .short("c")
+                        .long("capture")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("compression-threshold")
+                        .help("Compression threshold of the captured connection, packets are uncompressed when not given")
+                        .long("compression-threshold")
+                        .takes_value(true)
+                        .validator(number::<i32>)
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of worker threads [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("restart")
+                        .help("Import every chunk again, ignoring the ones recorded in the journal")
+                        .long("restart")
+                )
+                .arg(on_conflict_arg())
+                .arg(dry_run_arg())
+                .arg(no_backup_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Apply the messages recorded by listen --record to an existing minecraft world")
+                .arg(
+                    Arg::with_name("recording")
+                        .help("Recording file, or directory of recordings replayed from the oldest")
+                        .short("r")
+                        .long("recording")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("session")
+                        .help("Only replay the messages of this listen session")
+                        .long("session")
+                        .takes_value(true)
+                        .validator(number::<u64>)
+                )
+                .arg(
+                    Arg::with_name("connection")
+                        .help("Only replay the messages of this connection of the session")
+                        .long("connection")
+                        .requires("session")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of worker threads [default: number of cores]")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .validator(number::<usize>)
+                )
+                .arg(
+                    Arg::with_name("restart")
+                        .help("Replay every message again, ignoring the ones recorded in the journal")
+                        .long("restart")
+                )
+                .arg(on_conflict_arg())
+                .arg(dry_run_arg())
+                .arg(no_backup_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert JSON chunk dumps to the compact binary format")
+                .arg(
+                    Arg::with_name("patch")
+                        .help("Dumps to convert, anything accepted by bulk --patch")
+                        .short("p")
+                        .long("patch")
+                        .required(true)
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("Directory where the binary dumps are written")
+                        .short("d")
+                        .long("destination")
+                        .required(true)
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Restore the chunks overwritten by an import")
+                .arg(
+                    Arg::with_name("session")
+                        .help("Import session to undo [default: the latest one]")
+                        .short("s")
+                        .long("session")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("list")
+                        .help("List the import sessions which can be undone")
+                        .short("l")
+                        .long("list")
+                )
         )
         .get_matches();
-    let output = matches.value_of("output").unwrap().to_string();
+    let usage = matches.usage().to_string();
+    let output = matches.value_of("output").map(|e| e.to_string());
+    // Only convert, and find or stats on dumps, work without a world
+    let world = |subcommand: &str| output.clone().unwrap_or_else(|| missing_output(subcommand, &usage));
     match  matches.subcommand() {
         ("bulk", Some(matches)) => {
+            let output = world("bulk");
             let patch = matches.value_of("patch").unwrap();
-            if let Err(e) = run(&output, patch) {
-                eprintln!("{}", e);
+            let options = BulkOptions {
+                jobs: parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs),
+                resume: !matches.is_present("restart"),
+                quarantine: matches.value_of("quarantine").map(PathBuf::from),
+                on_conflict: parsed(matches, "on-conflict").unwrap_or_default(),
+                pick: parsed(matches, "pick").unwrap_or_default(),
+                dry_run: matches.is_present("dry-run"),
+                backup: !matches.is_present("no-backup"),
+            };
+            match run(&output, patch, options) {
+                Ok(failures) if failures.is_empty() => {},
+                Ok(failures) => {
+                    eprintln!("{} dumps failed", failures.len());
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
             }
         },
         ("listen", Some(matches)) => {
-            let port = matches.value_of("port").and_then(|port| port.parse().ok()).unwrap_or(4242u32);
-            let addr = format!("127.0.0.1:{}", port);
-            let server = TcpListener::bind(&addr).unwrap();
-            info!("Listening on {} ...", addr);
-            for stream in server.incoming() {
-                let path = output.clone();
-                spawn (move || {
-                    let provider = AnvilChunkProvider::new(&path);
-                    let mut websocket = accept(stream.unwrap()).unwrap();
-                    loop {
-                        if let Ok(msg) = websocket.read_message() {
-                            if msg.is_binary() || msg.is_text() {
-                                if let Ok(chunk) = serde_json::from_slice(&msg.into_data()) {
-                                    let chunk: PacketChunk = chunk;
-                                    let chunk_x = chunk.x;
-                                    let chunk_z = chunk.z;
-                                    let chunk = chunk.into();
-                                    match provider.save_chunk(chunk_x, chunk_z, chunk) {
-                                        Ok(_) => info!("{}:{} Patched !", chunk_x, chunk_z),
-                                        Err(e) => error!("{}:{} Failed to patch: {:?}", chunk_x, chunk_z, e),
-                                    }    
-                                } else {
-                                    warn!("Invalide packet received !");
-                                }
-                            } else {
-                                info!("Connection closed !");
-                                break
-                            }
-                        }
-                    }
-                });
+            let output = world("listen");
+            let port = parsed(matches, "port").unwrap_or(4242u16);
+            let mut auth = matches.value_of("auth-file").map(|e| Auth::load(e).expect("Invalid auth file")).unwrap_or_default();
+            if let Some(token) = matches.value_of("token") {
+                auth.token = Some(token.to_string());
+            }
+            if let Some(clients) = matches.values_of("allow-client") {
+                auth.clients.extend(clients.map(|e| e.to_string()));
+            }
+            let bind = matches.value_of("bind").unwrap_or("127.0.0.1");
+            if !auth.is_required() && !listen::is_loopback(bind) {
+                warn!("Listening on {} without authentication, anyone reaching the port can write to the world", bind);
+            }
+            let record = matches.value_of("record").map(|dir| {
+                let max_size = parsed(matches, "record-max-size").unwrap_or(64u64);
+                let keep = parsed(matches, "record-keep").unwrap_or(0);
+                Recorder::create(dir, max_size * 1024 * 1024, keep).expect("Failed to start the recording")
+            });
+            let options = ListenOptions {
+                bind: listen::address(bind, port),
+                max_connections: parsed(matches, "max-connections").unwrap_or(16),
+                jobs: parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs),
+                idle_timeout: Duration::from_secs(parsed(matches, "idle-timeout").unwrap_or(300)),
+                on_conflict: parsed(matches, "on-conflict").unwrap_or_default(),
+                dry_run: matches.is_present("dry-run"),
+                backup: !matches.is_present("no-backup"),
+                auth,
+                record,
+                http_bind: parsed::<u16>(matches, "http-port").map(|port| listen::address(bind, port)),
+            };
+            if let Err(e) = listen::run(&output, options) {
+                eprintln!("Listen failed: {}", e);
+                std::process::exit(1);
             }
         },
         ("find", Some(matches)) => {
@@ -283,80 +546,142 @@ async fn main() {
                     println!("{}", item.name);
                 }
             } else {
-                let oc = output.clone();
-                let target: Vec<String> = matches.values_of("block").expect("Block to find").into_iter().map(|e| e.to_string()).collect();
+                let output = match matches.value_of("dump") {
+                    Some(_) => output.clone().unwrap_or_default(),
+                    None => world("find"),
+                };
+                let sources = chunk_sources(&output, matches.value_of("dump"));
+                let values = |name| matches.values_of(name).map(|v| v.map(|e| e.to_string()).collect()).unwrap_or_else(Vec::new);
+                let mut query = FindQuery {
+                    blocks: values("block"),
+                    items: values("item"),
+                    min_count: parsed(matches, "min-count").unwrap_or(1),
+                    sign: matches.value_of("sign").map(|e| e.to_string()),
+                    sign_regex: matches.value_of("sign-regex").and_then(|e| Regex::new(e).ok()),
+                    spawners: values("spawner"),
+                };
+                // Only look for the default block when nothing else was asked for
+                if matches.occurrences_of("block") == 0 && query.wants_tile_entities() {
+                    query.blocks.clear();
+                }
                 let mut handles = vec![];
-                let addr = SyncArbiter::start(16, move || {
+                let jobs = parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs).max(1);
+                let addr = SyncArbiter::start(jobs, move || {
                     let output = output.clone();
-                    let target =  target.clone();
-                    FindActor::new(target, output)
+                    let query = query.clone();
+                    FindActor::new(query, output)
                 });
-                for dir in fs::read_dir(&oc).expect("Wrong map directory").filter(|e| e.is_ok()).map(|e| e.unwrap()) {
-                    if let Some(region) = RegionFile::new(dir.path()) {
-                       handles.push( addr.send(FindRequest(region)));
-                    }
+                for source in sources {
+                    handles.push(addr.send(FindRequest(source)));
                 }
                 for handle in handles {
                     let _ = handle.await;
                 }
             }
         },
-        _ => error!("Unknow subcommand"),
-    }
-}
-
-fn find(x: i32, z: i32, tag: nbt::CompoundTag, target: &[String]) -> Option<()> {
-    fn find_in_section(x: i32, z: i32, section: &nbt::CompoundTag, target: &[String]) -> Option<()> {
-        let palette = section.get_compound_tag_vec("Palette").ok()?;
-        let mut found = BTreeMap::new();
-        for item in palette {
-            for target in target {
-            if item.get_str("Name").ok() == Some(target) {
-                match found.entry(target) {
-                    btree_map::Entry::Occupied(mut e) => {
-                        *e.get_mut() += 1;
-                    },
-                    btree_map::Entry::Vacant(mut e) => {
-                        e.insert(1);
-                    }
-                };
+        ("stats", Some(matches)) => {
+            let output = match matches.value_of("dump") {
+                Some(_) => output.clone().unwrap_or_default(),
+                None => world("stats"),
+            };
+            let sources = chunk_sources(&output, matches.value_of("dump"));
+            let filter: Vec<String> = matches.values_of("block").map(|v| v.map(|e| e.to_string()).collect()).unwrap_or_else(Vec::new);
+            let mut handles = vec![];
+            let jobs = parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs).max(1);
+            let addr = SyncArbiter::start(jobs, move || {
+                StatsActor::new(filter.clone(), output.clone())
+            });
+            for source in sources {
+                handles.push(addr.send(StatsRequest(source)));
             }
-        }
-        }
-        if found.len() > 0 {
-            for (k,v) in found {
-                info!("{} 100 {} -> Found {} {}", x, z, v, k);
+            let mut stats = BlockStats::default();
+            for handle in handles {
+                if let Ok(Some(region)) = handle.await {
+                    stats.merge(region);
+                }
             }
-        }
-        Some(())
-    }
-
-    let level = tag.get_compound_tag("Level").ok()?;
-    let sections = level.get_compound_tag_vec("Sections").ok()?;
-    for section in sections {
-        let _ = find_in_section( x, z,section, target);
-    }
-    Some(())
-}
-
-#[derive(Debug)]
-struct RegionFile {
-    x: i32,
-    z: i32,
-    path: PathBuf,
-}
-
-impl RegionFile {
-    fn new(path: PathBuf) -> Option<RegionFile> {
-        let s: &str = path.file_stem()?.to_str()?;
-        let mut sp = s.split('.');
-        sp.next()?;
-        let x = sp.next()?.parse().ok()?;
-        let z = sp.next()?.parse().ok()?;
-        Some(RegionFile {
-            x,
-            z,
-            path,
-        })
+            match matches.value_of("format") {
+                Some("json") => println!("{}", serde_json::to_string_pretty(&stats).expect("Serialize stats")),
+                _ => stats.print_table(matches.is_present("levels")),
+            }
+        },
+        ("import-capture", Some(matches)) => {
+            let output = world("import-capture");
+            let capture_path = matches.value_of("capture").unwrap();
+            let threshold = parsed(matches, "compression-threshold");
+            let options = BulkOptions {
+                jobs: parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs),
+                resume: !matches.is_present("restart"),
+                quarantine: None,
+                on_conflict: parsed(matches, "on-conflict").unwrap_or_default(),
+                pick: Default::default(),
+                dry_run: matches.is_present("dry-run"),
+                backup: !matches.is_present("no-backup"),
+            };
+            match capture::import_capture(&output, capture_path, threshold, options) {
+                Ok(failures) if failures.is_empty() => {},
+                Ok(failures) => {
+                    eprintln!("{} packets failed", failures.len());
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+        },
+        ("replay", Some(matches)) => {
+            let output = world("replay");
+            let recording = matches.value_of("recording").unwrap();
+            let session = parsed(matches, "session");
+            let connection = parsed(matches, "connection");
+            let options = BulkOptions {
+                jobs: parsed(matches, "jobs").unwrap_or_else(bulk::default_jobs),
+                resume: !matches.is_present("restart"),
+                quarantine: None,
+                on_conflict: parsed(matches, "on-conflict").unwrap_or_default(),
+                pick: Default::default(),
+                dry_run: matches.is_present("dry-run"),
+                backup: !matches.is_present("no-backup"),
+            };
+            match record::replay(&output, recording, session, connection, options) {
+                Ok(failures) if failures.is_empty() => {},
+                Ok(failures) => {
+                    eprintln!("{} messages failed", failures.len());
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+        },
+        ("convert", Some(matches)) => {
+            let patch = matches.value_of("patch").unwrap();
+            let destination = matches.value_of("destination").unwrap();
+            match convert::convert(patch, destination) {
+                Ok((_, 0)) => {},
+                Ok((_, failed)) => {
+                    eprintln!("{} dumps failed", failed);
+                    std::process::exit(1);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+        },
+        ("undo", Some(matches)) => {
+            let output = world("undo");
+            if matches.is_present("list") {
+                for session in backup::sessions(&output).expect("Failed to list backups") {
+                    println!("{}", session.file_stem().and_then(|e| e.to_str()).unwrap_or_default());
+                }
+            } else if let Err(e) = backup::undo(&output, matches.value_of("session")) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => error!("Unknow subcommand"),
     }
 }
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::{ops, io::{Read}, io, collections::BTreeMap};
+use std::{ops, io::{Read}, io, collections::BTreeMap, path::Path};
 use nbt::CompoundTag;
 use serde::{Serialize, Deserialize};
 use byteorder::{BigEndian, ReadBytesExt};
@@ -8,6 +8,7 @@ use mc_varint::{VarIntRead, VarLongRead};
 const CHUNK_HEIGHT: i32 = 256;
 const SECTION_HEIGHT: i32 = 16;
 const MAX_BITS_PER_BLOCK: u8 = 8;
+pub const SECTION_VOLUME: usize = 16 * 16 * 16;
 
 type BlockId = i64;
 
@@ -109,7 +110,7 @@ impl <T: Read + Sized> ReadArrayExt for T {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HeightMaps {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     pub _type: String,
     pub name: String,
     pub value: HeightMapsValues,
@@ -117,23 +118,23 @@ pub struct HeightMaps {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HeightMapsValues {
-    #[serde(rename(deserialize = "MOTION_BLOCKING"))]
+    #[serde(rename = "MOTION_BLOCKING")]
     motion_blocking: Option<HeightMapsValue>,
-    #[serde(rename(deserialize = "MOTION_BLOCKING_NO_LEAVES"))]
+    #[serde(rename = "MOTION_BLOCKING_NO_LEAVES")]
     motion_blocking_no_leaves: Option<HeightMapsValue>,
-    #[serde(rename(deserialize = "OCEAN_FLOOR"))]
+    #[serde(rename = "OCEAN_FLOOR")]
     ocean_floor: Option<HeightMapsValue>,
-    #[serde(rename(deserialize = "OCEAN_FLOOR_WG"))]
+    #[serde(rename = "OCEAN_FLOOR_WG")]
     ocean_floor_wg: Option<HeightMapsValue>,
-    #[serde(rename(deserialize = "WORLD_SURFACE"))]
+    #[serde(rename = "WORLD_SURFACE")]
     world_surface: Option<HeightMapsValue>,
-    #[serde(rename(deserialize = "WORLD_SURFACE_WG"))]
+    #[serde(rename = "WORLD_SURFACE_WG")]
     world_surface_wg: Option<HeightMapsValue>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HeightMapsValue {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     _type: String,
     value: Vec<Vec<i64>>,
 }
@@ -152,10 +153,30 @@ pub struct PacketChunk {
     pub chunk_data: ChunkData,
     #[serde(rename(deserialize = "blockEntities"))]
     pub block_entities: serde_json::Value,
+    /// Capture time in milliseconds since the epoch, when recorded by the capture tool
+    #[serde(default)]
+    pub timestamp: Option<u64>,
 }
 
-impl Into<CompoundTag> for PacketChunk {
-    fn into(self) -> CompoundTag {
+impl PacketChunk {
+    /// Read a chunk dump, JSON, binary or raw packet, plain or compressed (`.gz`, `.zst`)
+    pub fn read_from<P: AsRef<Path>>(path: P) -> io::Result<PacketChunk> {
+        PacketChunk::parse(&crate::input::read_dump(path)?.data)
+    }
+
+    /// Parse a dump, binary dumps are recognized by their magic and raw Chunk Data packets by their id
+    pub fn parse(data: &[u8]) -> io::Result<PacketChunk> {
+        if crate::binary::is_binary(data) {
+            crate::binary::read(&mut &data[..])
+        } else if crate::packet::is_packet(data) {
+            crate::packet::read_chunk_data(data)
+        } else {
+            serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    /// Build the anvil chunk, failing instead of panicking on malformed chunk data
+    pub fn into_compound_tag(self) -> io::Result<CompoundTag> {
         let mut chunk_compound_tag = CompoundTag::new();
         let mut level_compound_tag = CompoundTag::new();
         level_compound_tag.insert_str("Status", "full");
@@ -171,12 +192,12 @@ impl Into<CompoundTag> for PacketChunk {
             //TODO heightmaps
             level_compound_tag.insert_compound_tag("Heightmaps", heightmaps_compound);
         }
-        level_compound_tag.insert_compound_tag_vec("TileEntities", vec![]);
+        level_compound_tag.insert_compound_tag_vec("TileEntities", tile_entities(self.x, self.z, &self.block_entities));
         level_compound_tag.insert_compound_tag_vec("Entities", vec![]);
         level_compound_tag.insert_i8("isLightOn", 1);
         level_compound_tag.insert_compound_tag_vec("TileTicks", vec![]);
 
-        let sections = self.chunk_data.read_data(self.bit_map).expect("Invalide packet").into();
+        let sections = self.chunk_data.read_data(self.bit_map)?.into();
 
         level_compound_tag.insert_compound_tag_vec("Sections", sections);
         level_compound_tag.insert_compound_tag_vec("PostProcessing", vec![]);
@@ -184,10 +205,33 @@ impl Into<CompoundTag> for PacketChunk {
         level_compound_tag.insert_compound_tag_vec("LiquidTicks", vec![]);
         chunk_compound_tag.insert_compound_tag("Level", level_compound_tag);
         chunk_compound_tag.insert_i32("DataVersion", 2230);
-        chunk_compound_tag
+        Ok(chunk_compound_tag)
     }
 }
 
+/// Convert the prismarine-nbt JSON block entities of a dump, the invalid ones are left out
+fn tile_entities(chunk_x: i32, chunk_z: i32, block_entities: &serde_json::Value) -> Vec<CompoundTag> {
+    let block_entities = match block_entities.as_array() {
+        Some(block_entities) => block_entities,
+        None => return vec![],
+    };
+    block_entities.iter()
+        .filter_map(|block_entity| {
+            let mut data = vec![];
+            let tag = crate::nbt_json::write_nbt(&mut data, block_entity)
+                .and_then(|_| nbt::decode::read_compound_tag(&mut &data[..])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))));
+            match tag {
+                Ok(tag) => Some(tag),
+                Err(e) => {
+                    warn!("{}:{} Skipping invalid block entity: {}", chunk_x, chunk_z, e);
+                    None
+                },
+            }
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkData {
     #[serde(rename(deserialize = "type"))]
@@ -237,7 +281,69 @@ impl Chunk {
     }
 }
 
+/// Number of bits used by each block in a section's `BlockStates` for a given palette length
+pub fn bits_per_block(palette_len: usize) -> usize {
+    let mut bits = 4;
+    while (1 << bits) < palette_len {
+        bits += 1;
+    }
+    bits
+}
+
+/// Unpack the 4096 palette indexes of a section's `BlockStates`, entries may span two longs
+pub fn unpack_block_states(states: &[i64], palette_len: usize) -> Vec<usize> {
+    let bits = bits_per_block(palette_len);
+    let mask = (1u64 << bits) - 1;
+    (0..SECTION_VOLUME).map(|i| {
+        let bit = i * bits;
+        let (index, offset) = (bit / 64, bit % 64);
+        let mut value = states.get(index).map(|e| *e as u64).unwrap_or(0) >> offset;
+        if offset + bits > 64 {
+            value |= states.get(index + 1).map(|e| *e as u64).unwrap_or(0) << (64 - offset);
+        }
+        (value & mask) as usize
+    }).collect()
+}
+
+/// Pack palette indexes into a section's `BlockStates`, the inverse of `unpack_block_states`
+pub fn pack_block_states(indexes: &[usize], palette_len: usize) -> Vec<i64> {
+    let bits = bits_per_block(palette_len);
+    let mut states = vec![0u64; (SECTION_VOLUME * bits + 63) / 64];
+    for (i, index) in indexes.iter().enumerate() {
+        let bit = i * bits;
+        let (long, offset) = (bit / 64, bit % 64);
+        states[long] |= (*index as u64) << offset;
+        if offset + bits > 64 {
+            states[long + 1] |= (*index as u64) >> (64 - offset);
+        }
+    }
+    states.into_iter().map(|e| e as i64).collect()
+}
+
+/// Palette entry of a global block state id, `None` for an id unknown to the palette
+pub fn block_state_tag(id: i32) -> Option<CompoundTag> {
+    let block = PALETTE.blocks.get(&(id as i64))?;
+    let mut tag = CompoundTag::new();
+    tag.insert_str("Name", &block.name);
+    if let Some(properties) = block.properties.clone() {
+        tag.insert_compound_tag("Properties", properties);
+    }
+    Some(tag)
+}
+
 impl ChunkData {
+    /// Chunk data as sent in the packet
+    pub fn new(data: Vec<u8>) -> Self {
+        ChunkData {
+            _type: "Buffer".to_string(),
+            data,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
     pub fn read_data(&self, mask: i32) -> io::Result<ParsedChunkData> {
         let mut buffer = std::io::Cursor::new(&self.data);
         let mut result = BTreeMap::new();
@@ -247,12 +353,22 @@ impl ChunkData {
             let palette = match bits_per_block {
                 0..=MAX_BITS_PER_BLOCK => {
                     let palette_len = i32::from(buffer.read_var_int()?);
-                    buffer.read_varint_array(palette_len as usize)?
+                    if palette_len < 0 || palette_len as usize > SECTION_VOLUME {
+                        return Err(invalid_data(format!("Invalid palette length {} in section {}", palette_len, section_y)));
+                    }
+                    let palette = buffer.read_varint_array(palette_len as usize)?;
+                    if let Some(id) = palette.iter().find(|id| !PALETTE.blocks.contains_key(&(**id as i64))) {
+                        return Err(invalid_data(format!("Unknown block state {} in section {}", id, section_y)));
+                    }
+                    palette
                 },
                 _ => vec![],
             };
             let data_len = i32::from(buffer.read_var_int()?);
-            let data = buffer.read_i64_array(data_len as usize).unwrap();
+            if data_len < 0 || data_len as usize > SECTION_VOLUME {
+                return Err(invalid_data(format!("Invalid data length {} in section {}", data_len, section_y)));
+            }
+            let data = buffer.read_i64_array(data_len as usize)?;
             result.insert(section_y, Chunk {
                 palette,
                 data,
@@ -260,4 +376,62 @@ impl ChunkData {
         }
         Ok(ParsedChunkData {chunks: result})
     }
-}
\ No newline at end of file
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexes(palette_len: usize) -> Vec<usize> {
+        (0..SECTION_VOLUME).map(|i| (i * 7 + i / 3) % palette_len).collect()
+    }
+
+    #[test]
+    fn bits() {
+        assert_eq!(bits_per_block(1), 4);
+        assert_eq!(bits_per_block(16), 4);
+        assert_eq!(bits_per_block(17), 5);
+        assert_eq!(bits_per_block(33), 6);
+        assert_eq!(bits_per_block(256), 8);
+        assert_eq!(bits_per_block(257), 9);
+    }
+
+    #[test]
+    fn round_trip() {
+        for palette_len in [2, 16, 20, 33, 100, 300].iter().cloned() {
+            let indexes = indexes(palette_len);
+            let states = pack_block_states(&indexes, palette_len);
+            assert_eq!(states.len(), (SECTION_VOLUME * bits_per_block(palette_len) + 63) / 64);
+            assert_eq!(unpack_block_states(&states, palette_len), indexes, "palette of {}", palette_len);
+        }
+    }
+
+    #[test]
+    fn entries_spanning_two_longs() {
+        // 5 bits per block, block 12 starts at bit 60: 4 bits in the first long, 1 in the second
+        let mut indexes = vec![0; SECTION_VOLUME];
+        indexes[12] = 0b10011;
+        let states = pack_block_states(&indexes, 20);
+        assert_eq!((states[0] as u64) >> 60, 0b0011);
+        assert_eq!(states[1] & 1, 1);
+        let unpacked = unpack_block_states(&states, 20);
+        assert_eq!(unpacked[12], 0b10011);
+        assert_eq!(unpacked[11], 0);
+        assert_eq!(unpacked[13], 0);
+    }
+
+    #[test]
+    fn all_bits_set() {
+        let indexes = vec![19; SECTION_VOLUME];
+        assert_eq!(unpack_block_states(&pack_block_states(&indexes, 20), 20), indexes);
+    }
+
+    #[test]
+    fn missing_longs_are_air() {
+        assert_eq!(unpack_block_states(&[], 2), vec![0; SECTION_VOLUME]);
+    }
+}
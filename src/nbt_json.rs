@@ -0,0 +1,245 @@
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde_json::{json, Map, Value};
+
+/// Tag names used by prismarine-nbt JSON, indexed by NBT tag id
+const TAG_TYPES: [&str; 13] = [
+    "end", "byte", "short", "int", "long", "float", "double", "byteArray", "string", "list", "compound", "intArray", "longArray",
+];
+/// Deepest nesting of lists and compounds read, deeper dumps would overflow the stack
+const MAX_DEPTH: usize = 128;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn tag_id(value: &Value) -> io::Result<u8> {
+    let name = value.as_str().unwrap_or_default();
+    TAG_TYPES.iter()
+        .position(|e| *e == name)
+        .map(|e| e as u8)
+        .ok_or_else(|| invalid(format!("Unknown NBT tag type {:?}", name)))
+}
+
+fn type_name(id: u8) -> io::Result<&'static str> {
+    TAG_TYPES.get(id as usize).cloned().ok_or_else(|| invalid(format!("Unknown NBT tag id {}", id)))
+}
+
+fn number(value: &Value) -> io::Result<f64> {
+    value.as_f64().ok_or_else(|| invalid(format!("Expected a number, got {}", value)))
+}
+
+fn array(value: &Value) -> io::Result<&Vec<Value>> {
+    value.as_array().ok_or_else(|| invalid(format!("Expected an array, got {}", value)))
+}
+
+/// Longs are `[high, low]` pairs of ints in prismarine-nbt JSON
+fn long(value: &Value) -> io::Result<i64> {
+    match value.as_array() {
+        Some(pair) if pair.len() == 2 => Ok(((number(&pair[0])? as i64) << 32) | (number(&pair[1])? as i64 & 0xFFFF_FFFF)),
+        _ => value.as_i64().ok_or_else(|| invalid(format!("Expected a long, got {}", value))),
+    }
+}
+
+fn json_long(value: i64) -> Value {
+    json!([(value >> 32) as i32, value as i32])
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    if s.len() > u16::MAX as usize {
+        return Err(invalid(format!("NBT string of {} bytes, longer than {}", s.len(), u16::MAX)));
+    }
+    w.write_u16::<BigEndian>(s.len() as u16)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut data = vec![0; r.read_u16::<BigEndian>()? as usize];
+    r.read_exact(&mut data)?;
+    String::from_utf8(data).map_err(|e| invalid(format!("Invalid NBT string: {}", e)))
+}
+
+fn read_len<R: Read>(r: &mut R) -> io::Result<i32> {
+    match r.read_i32::<BigEndian>()? {
+        len if len < 0 => Err(invalid(format!("Negative NBT length {}", len))),
+        len => Ok(len),
+    }
+}
+
+fn write_payload<W: Write>(w: &mut W, id: u8, value: &Value) -> io::Result<()> {
+    match id {
+        1 => w.write_i8(number(value)? as i8),
+        2 => w.write_i16::<BigEndian>(number(value)? as i16),
+        3 => w.write_i32::<BigEndian>(number(value)? as i32),
+        4 => w.write_i64::<BigEndian>(long(value)?),
+        5 => w.write_f32::<BigEndian>(number(value)? as f32),
+        6 => w.write_f64::<BigEndian>(number(value)?),
+        7 => {
+            let values = array(value)?;
+            w.write_i32::<BigEndian>(values.len() as i32)?;
+            values.iter().try_for_each(|e| w.write_i8(number(e)? as i8))
+        },
+        8 => write_string(w, value.as_str().ok_or_else(|| invalid(format!("Expected a string, got {}", value)))?),
+        9 => {
+            let values = array(&value["value"])?;
+            let id = tag_id(&value["type"])?;
+            w.write_u8(id)?;
+            w.write_i32::<BigEndian>(values.len() as i32)?;
+            values.iter().try_for_each(|e| write_payload(w, id, e))
+        },
+        10 => {
+            let tags = value.as_object().ok_or_else(|| invalid(format!("Expected a compound, got {}", value)))?;
+            for (name, tag) in tags.iter().filter(|(_, tag)| !tag.is_null()) {
+                let id = tag_id(&tag["type"])?;
+                w.write_u8(id)?;
+                write_string(w, name)?;
+                write_payload(w, id, &tag["value"])?;
+            }
+            w.write_u8(0)
+        },
+        11 => {
+            let values = array(value)?;
+            w.write_i32::<BigEndian>(values.len() as i32)?;
+            values.iter().try_for_each(|e| w.write_i32::<BigEndian>(number(e)? as i32))
+        },
+        12 => {
+            let values = array(value)?;
+            w.write_i32::<BigEndian>(values.len() as i32)?;
+            values.iter().try_for_each(|e| w.write_i64::<BigEndian>(long(e)?))
+        },
+        _ => Err(invalid(format!("Unexpected NBT tag id {}", id))),
+    }
+}
+
+fn read_payload<R: Read>(r: &mut R, id: u8, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!("NBT nested deeper than {} levels", MAX_DEPTH)));
+    }
+    Ok(match id {
+        1 => json!(r.read_i8()?),
+        2 => json!(r.read_i16::<BigEndian>()?),
+        3 => json!(r.read_i32::<BigEndian>()?),
+        4 => json_long(r.read_i64::<BigEndian>()?),
+        5 => json!(r.read_f32::<BigEndian>()?),
+        6 => json!(r.read_f64::<BigEndian>()?),
+        7 => {
+            let len = read_len(r)?;
+            Value::Array((0..len).map(|_| r.read_i8().map(|e| json!(e))).collect::<io::Result<_>>()?)
+        },
+        8 => json!(read_string(r)?),
+        9 => {
+            let id = r.read_u8()?;
+            let len = read_len(r)?;
+            let values = (0..len).map(|_| read_payload(r, id, depth + 1)).collect::<io::Result<Vec<_>>>()?;
+            json!({"type": type_name(id)?, "value": values})
+        },
+        10 => {
+            let mut tags = Map::new();
+            loop {
+                let id = r.read_u8()?;
+                if id == 0 {
+                    break;
+                }
+                let name = read_string(r)?;
+                let value = read_payload(r, id, depth + 1)?;
+                tags.insert(name, json!({"type": type_name(id)?, "value": value}));
+            }
+            Value::Object(tags)
+        },
+        11 => {
+            let len = read_len(r)?;
+            Value::Array((0..len).map(|_| r.read_i32::<BigEndian>().map(|e| json!(e))).collect::<io::Result<_>>()?)
+        },
+        12 => {
+            let len = read_len(r)?;
+            Value::Array((0..len).map(|_| r.read_i64::<BigEndian>().map(json_long)).collect::<io::Result<_>>()?)
+        },
+        _ => return Err(invalid(format!("Unexpected NBT tag id {}", id))),
+    })
+}
+
+/// Write a prismarine-nbt JSON root tag (`{"type": "compound", "name": ..., "value": ...}`) as uncompressed NBT
+pub fn write_nbt<W: Write>(w: &mut W, root: &Value) -> io::Result<()> {
+    let id = tag_id(&root["type"])?;
+    w.write_u8(id)?;
+    write_string(w, root["name"].as_str().unwrap_or_default())?;
+    write_payload(w, id, &root["value"])
+}
+
+/// Read an uncompressed NBT root tag as prismarine-nbt JSON
+pub fn read_nbt<R: Read>(r: &mut R) -> io::Result<Value> {
+    let id = r.read_u8()?;
+    let name = read_string(r)?;
+    let value = read_payload(r, id, 0)?;
+    Ok(json!({"type": type_name(id)?, "name": name, "value": value}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let root = json!({
+            "type": "compound",
+            "name": "root",
+            "value": {
+                "byte": {"type": "byte", "value": -1},
+                "short": {"type": "short", "value": 300},
+                "int": {"type": "int", "value": -70000},
+                "long": {"type": "long", "value": [-1, -2]},
+                "float": {"type": "float", "value": 1.5},
+                "double": {"type": "double", "value": -0.25},
+                "byteArray": {"type": "byteArray", "value": [1, -2, 3]},
+                "string": {"type": "string", "value": "minecraft:sign"},
+                "list": {"type": "list", "value": {"type": "int", "value": [1, 2, 3]}},
+                "compound": {"type": "compound", "value": {"text": {"type": "string", "value": "é"}}},
+                "intArray": {"type": "intArray", "value": [4, -5]},
+                "longArray": {"type": "longArray", "value": [[0, 1], [2, 3]]},
+            },
+        });
+        let mut data = vec![];
+        write_nbt(&mut data, &root).unwrap();
+        assert_eq!(read_nbt(&mut &data[..]).unwrap(), root);
+    }
+
+    #[test]
+    fn long_halves() {
+        assert_eq!(long(&json!([-1, -2])).unwrap(), -2);
+        assert_eq!(long(&json!([1, 0])).unwrap(), 1 << 32);
+        assert_eq!(json_long(-2), json!([-1, -2]));
+    }
+
+    #[test]
+    fn too_deep() {
+        // A list of lists nested past the limit
+        let mut data = vec![9, 0, 0];
+        for _ in 0..MAX_DEPTH + 10 {
+            data.extend_from_slice(&[9, 0, 0, 0, 1]);
+        }
+        let e = read_nbt(&mut &data[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn string_too_long() {
+        let root = json!({"type": "string", "name": "", "value": "a".repeat(u16::MAX as usize + 1)});
+        let e = write_nbt(&mut vec![], &root).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let root = json!({"type": "string", "name": "", "value": "a".repeat(u16::MAX as usize)});
+        assert!(write_nbt(&mut vec![], &root).is_ok());
+    }
+
+    #[test]
+    fn truncated_string() {
+        // String tag whose value claims 5 bytes, only 2 are there
+        let e = read_nbt(&mut &[8, 0, 0, 0, 5, b'a', b'b'][..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_type() {
+        assert!(write_nbt(&mut vec![], &json!({"type": "nope", "name": "", "value": 1})).is_err());
+        assert!(read_nbt(&mut &[13, 0, 0][..]).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{self, Read};
+use byteorder::{BigEndian, ReadBytesExt};
+use mc_varint::VarIntRead;
+
+use crate::models::{ChunkData, PacketChunk, ReadArrayExt};
+use crate::nbt_json::read_nbt;
+
+/// Id of the Chunk Data packet in the 1.15 play state
+pub const CHUNK_DATA: i32 = 0x22;
+/// Biomes of a full 1.15 chunk, 4x4x4 cells
+const BIOMES_LEN: usize = 1024;
+/// Largest chunk data accepted by the 1.15 client
+const MAX_DATA_SIZE: i32 = 2 * 1024 * 1024;
+/// NBT tag id of the heightmaps
+const TAG_COMPOUND: u8 = 10;
+/// One block entity per block of a chunk
+const MAX_BLOCK_ENTITIES: i32 = 16 * 16 * 256;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Raw Chunk Data packets are recognized by their first fields: the packet id, the position, a ground up flag,
+/// a bit map of at most 16 sections and the heightmaps compound. A JSON dump starting with the id byte (`"`) can't match
+pub fn is_packet(data: &[u8]) -> bool {
+    // Packet id, x, z, ground up flag and at least the first byte of the bit map
+    if data.len() < 11 || data[0] != CHUNK_DATA as u8 || data[9] > 1 {
+        return false;
+    }
+    let mut r = &data[10..];
+    match r.read_var_int() {
+        Ok(bit_map) => (0..=0xFFFF).contains(&i32::from(bit_map)) && r.first() == Some(&TAG_COMPOUND),
+        Err(_) => false,
+    }
+}
+
+/// Chunk coordinates of a raw Chunk Data packet, read from its first fields only
+pub fn read_position(data: &[u8]) -> Option<(i32, i32)> {
+    if !is_packet(data) {
+        return None;
+    }
+    let mut r = &data[1..];
+    let x = r.read_i32::<BigEndian>().ok()?;
+    let z = r.read_i32::<BigEndian>().ok()?;
+    Some((x, z))
+}
+
+/// Decode a raw 1.15 Chunk Data packet, packet id included
+pub fn read_chunk_data(data: &[u8]) -> io::Result<PacketChunk> {
+    let mut r = data;
+    let id = i32::from(r.read_var_int()?);
+    if id != CHUNK_DATA {
+        return Err(invalid(format!("Not a Chunk Data packet (id {:#x})", id)));
+    }
+    read_chunk_data_body(&mut r)
+}
+
+/// Decode the fields of a Chunk Data packet following its packet id
+pub fn read_chunk_data_body<R: Read>(r: &mut R) -> io::Result<PacketChunk> {
+    let x = r.read_i32::<BigEndian>()?;
+    let z = r.read_i32::<BigEndian>()?;
+    let ground_up = r.read_u8()? != 0;
+    let bit_map = i32::from(r.read_var_int()?);
+    let heightmaps = serde_json::from_value(read_nbt(r)?).map_err(|e| invalid(format!("Invalid heightmaps: {}", e)))?;
+    let biomes = match ground_up {
+        true => Some(r.read_i32_array(BIOMES_LEN)?),
+        false => None,
+    };
+    let size = i32::from(r.read_var_int()?);
+    if !(0..=MAX_DATA_SIZE).contains(&size) {
+        return Err(invalid(format!("Invalid chunk data size {}", size)));
+    }
+    let data = r.read_u8_array(size as usize)?;
+    let count = i32::from(r.read_var_int()?);
+    if !(0..=MAX_BLOCK_ENTITIES).contains(&count) {
+        return Err(invalid(format!("Invalid block entity count {}", count)));
+    }
+    let mut block_entities = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        block_entities.push(read_nbt(r)?);
+    }
+    Ok(PacketChunk {
+        x,
+        z,
+        ground_up,
+        bit_map,
+        heightmaps: Some(heightmaps),
+        biomes,
+        chunk_data: ChunkData::new(data),
+        block_entities: serde_json::Value::Array(block_entities),
+        timestamp: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use serde_json::json;
+    use crate::nbt_json::write_nbt;
+
+    fn packet(ground_up: bool) -> Vec<u8> {
+        let mut data = vec![CHUNK_DATA as u8];
+        data.write_i32::<BigEndian>(-2).unwrap();
+        data.write_i32::<BigEndian>(5).unwrap();
+        data.push(ground_up as u8);
+        // Bit map, a single byte varint
+        data.push(0b11);
+        write_nbt(&mut data, &json!({
+            "type": "compound",
+            "name": "",
+            "value": {"MOTION_BLOCKING": {"type": "longArray", "value": [[0, 1]]}},
+        })).unwrap();
+        if ground_up {
+            for i in 0..BIOMES_LEN as i32 {
+                data.write_i32::<BigEndian>(i % 3).unwrap();
+            }
+        }
+        // Chunk data size then data
+        data.push(3);
+        data.extend_from_slice(&[7, 8, 9]);
+        // Block entity count then block entities
+        data.push(1);
+        write_nbt(&mut data, &json!({
+            "type": "compound",
+            "name": "",
+            "value": {"id": {"type": "string", "value": "minecraft:sign"}},
+        })).unwrap();
+        data
+    }
+
+    #[test]
+    fn read_packet() {
+        let data = packet(true);
+        assert!(is_packet(&data));
+        assert_eq!(read_position(&data), Some((-2, 5)));
+        let chunk = read_chunk_data(&data).unwrap();
+        assert_eq!((chunk.x, chunk.z, chunk.ground_up, chunk.bit_map), (-2, 5, true, 0b11));
+        assert!(chunk.heightmaps.is_some());
+        let biomes = chunk.biomes.unwrap();
+        assert_eq!((biomes.len(), biomes[1], biomes[5]), (BIOMES_LEN, 1, 2));
+        assert_eq!(chunk.chunk_data.bytes(), &[7, 8, 9]);
+        assert_eq!(chunk.block_entities[0]["value"]["id"]["value"], "minecraft:sign");
+        assert_eq!(chunk.timestamp, None);
+    }
+
+    #[test]
+    fn read_body_without_biomes() {
+        let data = packet(false);
+        let chunk = read_chunk_data_body(&mut &data[1..]).unwrap();
+        assert!(!chunk.ground_up);
+        assert!(chunk.biomes.is_none());
+        assert_eq!(chunk.chunk_data.bytes(), &[7, 8, 9]);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = packet(true);
+        assert!(read_chunk_data(&data[..data.len() - 2]).is_err());
+        assert!(read_chunk_data_body(&mut &data[1..40]).is_err());
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        let mut data = packet(false);
+        // Chunk data size right after the heightmaps, as a negative varint
+        let size = data.iter().rposition(|e| *e == 3).unwrap();
+        data.splice(size..size + 1, vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        assert!(read_chunk_data(&data).is_err());
+    }
+
+    #[test]
+    fn not_a_packet() {
+        assert!(!is_packet(b"\"x\": 1, \"z\": 2, \"groundUp\": true}"));
+        assert!(!is_packet(br#"{"x": 1}"#));
+        assert!(!is_packet(&[CHUNK_DATA as u8]));
+        // Ground up flag out of range
+        let mut data = packet(true);
+        data[9] = 2;
+        assert!(!is_packet(&data));
+        // No heightmaps compound after the bit map
+        let mut data = packet(true);
+        data[11] = 8;
+        assert!(!is_packet(&data));
+        assert!(read_chunk_data(b"\x21\x00").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REFRESH_RATE: Duration = Duration::from_millis(500);
+/// Interval of the progress lines when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Chunk counters shared by the workers of an import
+pub struct Progress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    /// Dumps found in the journal
+    imported: AtomicUsize,
+    /// Captures left out for a better capture of their chunk
+    superseded: AtomicUsize,
+    /// Chunks left as they were by the conflict policy
+    kept: AtomicUsize,
+    failed: AtomicUsize,
+    start: Instant,
+    stop: AtomicBool,
+    /// Stderr is a terminal
+    tty: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Arc<Progress> {
+        Arc::new(Progress {
+            total: AtomicUsize::new(total),
+            done: AtomicUsize::new(0),
+            imported: AtomicUsize::new(0),
+            superseded: AtomicUsize::new(0),
+            kept: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            start: Instant::now(),
+            stop: AtomicBool::new(false),
+            tty: atty::is(atty::Stream::Stderr),
+        })
+    }
+
+    /// Dumps discovered while streaming an archive
+    pub fn add_total(&self, count: usize) {
+        self.total.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn chunk_done(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A dump imported by an earlier run
+    pub fn chunk_already_imported(&self) {
+        self.imported.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A capture left out because a better capture of the chunk was applied
+    pub fn chunk_superseded(&self) {
+        self.superseded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A chunk the conflict policy kept as it was in the world
+    pub fn chunk_kept(&self) {
+        self.kept.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn chunk_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Chunks which were counted as done but could not be written
+    pub fn chunks_lost(&self, count: usize) {
+        self.done.fetch_sub(count, Ordering::Relaxed);
+        self.failed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// A chunk counted as done which was replaced by a better capture
+    pub fn chunk_replaced(&self) {
+        self.done.fetch_sub(1, Ordering::Relaxed);
+        self.superseded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Dumps handled without being written
+    fn skipped(&self) -> usize {
+        self.imported.load(Ordering::Relaxed) + self.superseded.load(Ordering::Relaxed) + self.kept.load(Ordering::Relaxed)
+    }
+
+    fn throughput(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.done.load(Ordering::Relaxed) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn line(&self) -> String {
+        let done = self.done.load(Ordering::Relaxed);
+        let skipped = self.skipped();
+        let failed = self.failed();
+        let throughput = self.throughput();
+        let total = self.total.load(Ordering::Relaxed);
+        let remaining = total.saturating_sub(done + skipped + failed);
+        let eta = if throughput > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / throughput))
+        } else {
+            "?".to_string()
+        };
+        format!("{}/{} chunks, {:.0} chunks/s, ETA {}, {} skipped, {} failed", done + skipped, total, throughput, eta, skipped, failed)
+    }
+
+    /// Refresh a progress line on stderr until `finish` is called
+    pub fn display(self: &Arc<Self>) -> thread::JoinHandle<()> {
+        let progress = self.clone();
+        thread::spawn(move || {
+            let mut logged = Instant::now();
+            while !progress.stop.load(Ordering::Relaxed) {
+                if progress.tty {
+                    eprint!("\r{}\x1b[K", progress.line());
+                    let _ = std::io::stderr().flush();
+                } else if logged.elapsed() >= LOG_INTERVAL {
+                    eprintln!("{}", progress.line());
+                    logged = Instant::now();
+                }
+                thread::sleep(REFRESH_RATE);
+            }
+        })
+    }
+
+    /// Stop the progress line and print the import summary
+    pub fn finish(&self, display: thread::JoinHandle<()>, dry_run: bool) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = display.join();
+        if self.tty {
+            eprintln!("\r{}\x1b[K", self.line());
+        }
+        eprintln!(
+            "{} {} chunks in {} ({:.0} chunks/s), {} already imported, {} superseded by a better capture, {} kept by --on-conflict, {} failed",
+            if dry_run { "Would import" } else { "Imported" },
+            self.done.load(Ordering::Relaxed),
+            format_duration(self.start.elapsed()),
+            self.throughput(),
+            self.imported.load(Ordering::Relaxed),
+            self.superseded.load(Ordering::Relaxed),
+            self.kept.load(Ordering::Relaxed),
+            self.failed(),
+        );
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
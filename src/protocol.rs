@@ -0,0 +1,338 @@
+//! Acknowledgement protocol of `listen`.
+//!
+//! A chunk message may be wrapped in an envelope carrying an id, the server then answers it with a
+//! text message. JSON messages use `{"id": ..., "version": ..., "chunk": <JSON dump>}`, binary
+//! messages start with the `D2MA` magic, a `u32` (big-endian) header length and a JSON header
+//! `{"id": ..., "version": ...}`, followed by the dump (binary, raw packet or JSON). `version`, the
+//! protocol version of the client, is optional. Messages without an envelope get no answer.
+use std::io::{self, Read};
+use byteorder::{BigEndian, ReadBytesExt};
+use serde_json::{json, Map, Value};
+
+use crate::models::PacketChunk;
+use crate::writer::{WriteError, Written};
+
+/// Magic of the binary envelope
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"D2MA";
+/// Protocol versions of 1.15, 1.15.1 and 1.15.2, the only ones the dumps are decoded for
+pub const PROTOCOL_VERSIONS: [i64; 3] = [573, 575, 578];
+/// Largest JSON header of a binary envelope
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+/// Why a chunk was not written, sent back to the client
+#[derive(Debug)]
+pub struct Rejection {
+    /// `decode`, `version`, `load`, `backup`, `save` or `stopped`
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn decode<E: std::fmt::Display>(e: E) -> Self {
+        Self {
+            kind: "decode",
+            message: e.to_string(),
+        }
+    }
+
+    /// Whether sending the same chunk again may work
+    pub fn retry(&self) -> bool {
+        matches!(self.kind, "backup" | "save" | "stopped")
+    }
+
+    /// The client sent something which is not a chunk dump
+    pub fn is_invalid(&self) -> bool {
+        matches!(self.kind, "decode" | "version")
+    }
+}
+
+impl From<WriteError> for Rejection {
+    fn from(e: WriteError) -> Self {
+        let kind = match e {
+            WriteError::Load(_) => "load",
+            WriteError::Backup(_) => "backup",
+            WriteError::Save(_) => "save",
+            WriteError::Stopped => "stopped",
+        };
+        Self {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// A chunk message, `reply_to` is the id to answer when the message had an envelope
+pub struct Request {
+    pub reply_to: Option<Value>,
+    pub chunk: Result<PacketChunk, Rejection>,
+}
+
+fn check_version(version: Option<&Value>) -> Result<(), Rejection> {
+    match version {
+        None | Some(Value::Null) => Ok(()),
+        Some(version) if version.as_i64().map_or(false, |e| PROTOCOL_VERSIONS.contains(&e)) => Ok(()),
+        Some(version) => Err(Rejection {
+            kind: "version",
+            message: format!("Unsupported protocol version {}, only 1.15 ({:?}) is supported", version, PROTOCOL_VERSIONS),
+        }),
+    }
+}
+
+fn open_json(mut fields: Map<String, Value>) -> Request {
+    let reply_to = Some(fields.remove("id").unwrap_or(Value::Null));
+    let chunk = check_version(fields.get("version"))
+        .and_then(|_| serde_json::from_value(fields.remove("chunk").unwrap_or(Value::Null)).map_err(Rejection::decode));
+    Request {
+        reply_to,
+        chunk,
+    }
+}
+
+/// Split a binary envelope in its JSON header and the dump following it
+fn split_envelope(data: &[u8]) -> io::Result<(Value, &[u8])> {
+    let mut r = &data[ENVELOPE_MAGIC.len()..];
+    let len = r.read_u32::<BigEndian>()?;
+    if len > MAX_HEADER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Envelope header too large ({} bytes)", len)));
+    }
+    let mut header = vec![0; len as usize];
+    r.read_exact(&mut header)?;
+    let header = serde_json::from_slice(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((header, r))
+}
+
+fn open_binary(data: &[u8]) -> Request {
+    match split_envelope(data) {
+        Ok((header, dump)) => Request {
+            reply_to: Some(header.get("id").cloned().unwrap_or(Value::Null)),
+            chunk: check_version(header.get("version")).and_then(|_| PacketChunk::parse(dump).map_err(Rejection::decode)),
+        },
+        Err(e) => Request {
+            reply_to: Some(Value::Null),
+            chunk: Err(Rejection::decode(format!("Invalid envelope: {}", e))),
+        },
+    }
+}
+
+/// Take a chunk out of a JSON value, plain JSON dump or envelope
+pub fn open_value(value: Value) -> Request {
+    match value {
+        Value::Object(fields) if fields.contains_key("chunk") => open_json(fields),
+        value => Request {
+            reply_to: None,
+            chunk: serde_json::from_value(value).map_err(Rejection::decode),
+        },
+    }
+}
+
+/// Take a chunk out of a text message, plain JSON dump or envelope
+pub fn open_text(text: &str) -> Request {
+    match serde_json::from_str(text) {
+        Ok(value) => open_value(value),
+        Err(e) => Request {
+            reply_to: None,
+            chunk: Err(Rejection::decode(e)),
+        },
+    }
+}
+
+/// Take a chunk out of a binary message, plain dump or envelope
+pub fn open_binary_message(data: &[u8]) -> Request {
+    match data.starts_with(ENVELOPE_MAGIC) {
+        true => open_binary(data),
+        false => Request {
+            reply_to: None,
+            chunk: PacketChunk::parse(data).map_err(Rejection::decode),
+        },
+    }
+}
+
+/// The dump carried by a message, taken out of its envelope
+pub fn dump_data(data: Vec<u8>, text: bool) -> Result<Vec<u8>, Rejection> {
+    if text {
+        return match serde_json::from_slice(&data) {
+            Ok(Value::Object(fields)) if fields.contains_key("chunk") => {
+                check_version(fields.get("version"))?;
+                serde_json::to_vec(&fields["chunk"]).map_err(Rejection::decode)
+            },
+            _ => Ok(data),
+        };
+    }
+    if !data.starts_with(ENVELOPE_MAGIC) {
+        return Ok(data);
+    }
+    let (header, dump) = split_envelope(&data).map_err(|e| Rejection::decode(format!("Invalid envelope: {}", e)))?;
+    check_version(header.get("version"))?;
+    Ok(dump.to_vec())
+}
+
+/// Answer to the chunk message `id`, in dry run mode `result` is what would have been done
+pub fn reply(id: Value, result: &Result<(i32, i32, Written), Rejection>, dry_run: bool) -> Value {
+    match result {
+        Ok((x, z, written)) if dry_run => json!({"id": id, "ok": true, "x": x, "z": z, "result": written.name(), "dry_run": true}),
+        Ok((x, z, written)) => json!({"id": id, "ok": true, "x": x, "z": z, "result": written.name()}),
+        Err(rejection) => json!({
+            "id": id,
+            "ok": false,
+            "error": {"kind": rejection.kind, "message": rejection.message, "retry": rejection.retry()},
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn dump() -> Value {
+        json!({"x": 3, "z": -7, "groundUp": true, "bitMap": 0, "chunkData": {"type": "Buffer", "data": []}, "blockEntities": []})
+    }
+
+    fn binary_dump() -> Vec<u8> {
+        let mut data = vec![];
+        crate::binary::write(&mut data, &serde_json::from_value(dump()).unwrap()).unwrap();
+        data
+    }
+
+    fn envelope(header: &[u8], dump: &[u8]) -> Vec<u8> {
+        let mut data = ENVELOPE_MAGIC.to_vec();
+        data.write_u32::<BigEndian>(header.len() as u32).unwrap();
+        data.extend_from_slice(header);
+        data.extend_from_slice(dump);
+        data
+    }
+
+    fn position(request: &Request) -> (i32, i32) {
+        let chunk = request.chunk.as_ref().unwrap();
+        (chunk.x, chunk.z)
+    }
+
+    fn rejection(request: Request) -> Rejection {
+        match request.chunk {
+            Ok(_) => panic!("chunk accepted"),
+            Err(rejection) => rejection,
+        }
+    }
+
+    #[test]
+    fn plain_dumps_get_no_answer() {
+        let request = open_text(&dump().to_string());
+        assert_eq!(request.reply_to, None);
+        assert_eq!(position(&request), (3, -7));
+        let request = open_binary_message(&binary_dump());
+        assert_eq!(request.reply_to, None);
+        assert_eq!(position(&request), (3, -7));
+    }
+
+    #[test]
+    fn json_envelope() {
+        let request = open_text(&json!({"id": "a1", "version": 578, "chunk": dump()}).to_string());
+        assert_eq!(request.reply_to, Some(json!("a1")));
+        assert_eq!(position(&request), (3, -7));
+        let request = open_text(&json!({"chunk": dump()}).to_string());
+        assert_eq!(request.reply_to, Some(Value::Null));
+        assert_eq!(position(&request), (3, -7));
+    }
+
+    #[test]
+    fn invalid_json() {
+        let request = open_text("{\"x\": 3");
+        assert_eq!(request.reply_to, None);
+        assert_eq!(rejection(request).kind, "decode");
+        let request = open_text(&json!({"id": 1, "chunk": {"x": 3}}).to_string());
+        assert_eq!(request.reply_to, Some(json!(1)));
+        let rejection = rejection(request);
+        assert_eq!(rejection.kind, "decode");
+        assert!(rejection.is_invalid() && !rejection.retry());
+    }
+
+    #[test]
+    fn versions() {
+        for version in PROTOCOL_VERSIONS.iter() {
+            assert!(open_text(&json!({"version": version, "chunk": dump()}).to_string()).chunk.is_ok());
+        }
+        assert!(open_text(&json!({"version": null, "chunk": dump()}).to_string()).chunk.is_ok());
+        for version in [json!(754), json!("578"), json!(578.5)].iter() {
+            let request = open_text(&json!({"id": 2, "version": version, "chunk": dump()}).to_string());
+            assert_eq!(request.reply_to, Some(json!(2)));
+            let rejection = rejection(request);
+            assert_eq!(rejection.kind, "version", "version {}", version);
+            assert!(rejection.is_invalid() && !rejection.retry());
+        }
+    }
+
+    #[test]
+    fn binary_envelope() {
+        let request = open_binary_message(&envelope(br#"{"id": 5, "version": 575}"#, &binary_dump()));
+        assert_eq!(request.reply_to, Some(json!(5)));
+        assert_eq!(position(&request), (3, -7));
+        // The dump of a binary envelope may also be JSON
+        let request = open_binary_message(&envelope(b"{}", dump().to_string().as_bytes()));
+        assert_eq!(request.reply_to, Some(Value::Null));
+        assert_eq!(position(&request), (3, -7));
+        let request = open_binary_message(&envelope(br#"{"id": 5, "version": 340}"#, &binary_dump()));
+        assert_eq!(request.reply_to, Some(json!(5)));
+        assert_eq!(rejection(request).kind, "version");
+    }
+
+    #[test]
+    fn invalid_binary_envelope() {
+        // Header length, but no header
+        let mut data = ENVELOPE_MAGIC.to_vec();
+        data.write_u32::<BigEndian>(10).unwrap();
+        data.extend_from_slice(b"{}");
+        let request = open_binary_message(&data);
+        assert_eq!(request.reply_to, Some(Value::Null));
+        assert_eq!(rejection(request).kind, "decode");
+        // No header length
+        assert_eq!(rejection(open_binary_message(b"D2MA\0")).kind, "decode");
+        // Header which is not JSON
+        assert_eq!(rejection(open_binary_message(&envelope(b"{id", &binary_dump()))).kind, "decode");
+    }
+
+    #[test]
+    fn oversized_header() {
+        let header = vec![b' '; MAX_HEADER_LEN as usize + 1];
+        let request = open_binary_message(&envelope(&header, &binary_dump()));
+        assert_eq!(request.reply_to, Some(Value::Null));
+        let rejection = rejection(request);
+        assert_eq!(rejection.kind, "decode");
+        assert!(rejection.message.contains("too large"), "{}", rejection.message);
+        // The largest header allowed
+        let mut header = b"{}".to_vec();
+        header.resize(MAX_HEADER_LEN as usize, b' ');
+        assert!(open_binary_message(&envelope(&header, &binary_dump())).chunk.is_ok());
+    }
+
+    #[test]
+    fn dump_data_strips_envelopes() {
+        let plain = dump().to_string().into_bytes();
+        assert_eq!(dump_data(plain.clone(), true).unwrap(), plain);
+        let data = dump_data(json!({"id": 1, "version": 578, "chunk": dump()}).to_string().into_bytes(), true).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&data).unwrap(), dump());
+        assert_eq!(dump_data(binary_dump(), false).unwrap(), binary_dump());
+        assert_eq!(dump_data(envelope(br#"{"id": 1}"#, &binary_dump()), false).unwrap(), binary_dump());
+        // Text which is not JSON is kept as is, it is rejected when decoded
+        assert_eq!(dump_data(b"{x".to_vec(), true).unwrap(), b"{x");
+    }
+
+    #[test]
+    fn dump_data_rejections() {
+        let text = json!({"version": 1, "chunk": dump()}).to_string().into_bytes();
+        assert_eq!(dump_data(text, true).unwrap_err().kind, "version");
+        assert_eq!(dump_data(envelope(br#"{"version": 1}"#, &binary_dump()), false).unwrap_err().kind, "version");
+        let header = vec![b' '; MAX_HEADER_LEN as usize + 1];
+        assert_eq!(dump_data(envelope(&header, &binary_dump()), false).unwrap_err().kind, "decode");
+        assert_eq!(dump_data(b"D2MA\0\0".to_vec(), false).unwrap_err().kind, "decode");
+    }
+
+    #[test]
+    fn replies() {
+        let rejection = Rejection {
+            kind: "save",
+            message: "disk full".to_string(),
+        };
+        let reply = reply(json!(4), &Err(rejection), false);
+        assert_eq!(reply, json!({"id": 4, "ok": false, "error": {"kind": "save", "message": "disk full", "retry": true}}));
+    }
+}
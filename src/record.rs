@@ -0,0 +1,205 @@
+use std::{fs, io, io::{BufWriter, Write}};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use crate::bulk::{session, BulkOptions, Dispatcher, Dump, Failure, FailureKind};
+use crate::input::{for_each_line, open_lines, RawDump};
+use crate::protocol;
+
+const RECORD_PREFIX: &str = "listen-";
+const RECORD_EXTENSION: &str = "ndjson";
+
+/// One message received by `listen`, a line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// Reception time in milliseconds since the epoch
+    pub time: u64,
+    /// Start time of the `listen` session in milliseconds since the epoch
+    pub session: u64,
+    /// Connection number, in the order of the connections of the session
+    pub connection: usize,
+    pub peer: String,
+    /// `text` or `binary`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Text messages as is, binary messages in base64
+    pub data: String,
+}
+
+impl Record {
+    fn payload(self) -> Result<(Vec<u8>, bool), String> {
+        match self.kind.as_str() {
+            "text" => Ok((self.data.into_bytes(), true)),
+            "binary" => base64::decode(&self.data).map(|data| (data, false)).map_err(|e| e.to_string()),
+            kind => Err(format!("Unknown message type {:?}", kind)),
+        }
+    }
+}
+
+struct RecordFile {
+    file: BufWriter<fs::File>,
+    size: u64,
+}
+
+/// Writes every message received by `listen` to `<dir>/listen-<ms>.ndjson`, starting a new file
+/// once `max_size` bytes are written and only keeping the `keep` latest files (all of them for 0)
+pub struct Recorder {
+    dir: PathBuf,
+    /// Start time of the session, written in its records
+    session: u64,
+    max_size: u64,
+    keep: usize,
+    inner: Mutex<RecordFile>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Recordings of a directory, oldest first
+fn recordings(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.file_name().and_then(|e| e.to_str()).map_or(false, |name| {
+            name.starts_with(RECORD_PREFIX) && name.contains(&format!(".{}", RECORD_EXTENSION))
+        }))
+        .collect();
+    // Names only differ by their start time
+    paths.sort_by_key(|path| path.file_name().and_then(|e| e.to_str()).and_then(|e| e[RECORD_PREFIX.len()..].split('.').next()?.parse::<u64>().ok()));
+    Ok(paths)
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(dir: P, max_size: u64, keep: usize) -> io::Result<Recorder> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let session = now();
+        info!("Recording session {}", session);
+        let inner = Mutex::new(Recorder::open(&dir)?);
+        Ok(Recorder {
+            dir,
+            session,
+            max_size,
+            keep,
+            inner,
+        })
+    }
+
+    fn open(dir: &Path) -> io::Result<RecordFile> {
+        let mut time = now();
+        // Two files started in the same millisecond
+        let path = loop {
+            let path = dir.join(format!("{}{}.{}", RECORD_PREFIX, time, RECORD_EXTENSION));
+            if !path.exists() {
+                break path;
+            }
+            time += 1;
+        };
+        info!("Recording messages to {}", path.display());
+        Ok(RecordFile {
+            file: BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).open(path)?),
+            size: 0,
+        })
+    }
+
+    /// Remove the oldest recordings beyond `keep`
+    fn prune(&self) -> io::Result<()> {
+        let paths = recordings(&self.dir)?;
+        if self.keep == 0 || paths.len() <= self.keep {
+            return Ok(());
+        }
+        for path in &paths[..paths.len() - self.keep] {
+            debug!("Removing {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Record a text (`text`) or binary message received from connection `connection`
+    pub fn record(&self, connection: usize, peer: &str, data: &[u8], text: bool) -> io::Result<()> {
+        let record = Record {
+            time: now(),
+            session: self.session,
+            connection,
+            peer: peer.to_string(),
+            kind: match text {
+                true => "text",
+                false => "binary",
+            }.to_string(),
+            data: match text {
+                true => String::from_utf8_lossy(data).into_owned(),
+                false => base64::encode(data),
+            },
+        };
+        let mut line = serde_json::to_vec(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        let mut inner = self.inner.lock().unwrap();
+        if inner.size > 0 && inner.size + line.len() as u64 > self.max_size {
+            inner.file.flush()?;
+            *inner = Recorder::open(&self.dir)?;
+            self.prune()?;
+        }
+        inner.file.write_all(&line)?;
+        // Whatever happens to the server, the messages received so far are on disk
+        inner.file.flush()?;
+        inner.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Apply the messages of a recording (a file, or a directory of recordings replayed oldest first) to the
+/// world, only the ones of session `session_filter` and connection `connection` when given
+pub fn replay(output: &str, recording: &str, session_filter: Option<u64>, connection: Option<usize>, options: BulkOptions) -> io::Result<Vec<Failure>> {
+    let recording = fs::canonicalize(recording)?;
+    let files = match recording.is_dir() {
+        true => recordings(&recording)?,
+        false => vec![recording.clone()],
+    };
+    session(output, options, 0, |import| {
+        let dispatcher = Dispatcher::new(import);
+        let mut replayed = 0;
+        for file in &files {
+            let lines = match open_lines(file) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    dispatcher.fail_input(file.clone(), e.to_string());
+                    continue;
+                },
+            };
+            let replayed_file = for_each_line(lines, file, |line| {
+                let record = match serde_json::from_slice::<Record>(&line.data) {
+                    Ok(record) => record,
+                    Err(e) => return dispatcher.fail(line.label, FailureKind::Parse, format!("Invalid record: {}", e)),
+                };
+                if session_filter.map_or(false, |session| session != record.session)
+                    || connection.map_or(false, |connection| connection != record.connection) {
+                    return;
+                }
+                let time = record.time;
+                let data = match record.payload().and_then(|(data, text)| protocol::dump_data(data, text).map_err(|e| e.message)) {
+                    Ok(data) => data,
+                    Err(e) => return dispatcher.fail(line.label, FailureKind::Parse, e),
+                };
+                let raw = RawDump {
+                    label: line.label,
+                    data,
+                    named: false,
+                    // Later messages win when a chunk was received several times
+                    mtime: Some(time),
+                };
+                replayed += 1;
+                match raw.position() {
+                    Some((x, z)) => dispatcher.send(x, z, Dump::Raw(raw)),
+                    None => dispatcher.fail(raw.label, FailureKind::Parse, "No chunk position".to_string()),
+                }
+            });
+            if let Err(e) = replayed_file {
+                dispatcher.fail_input(file.clone(), e.to_string());
+            }
+        }
+        info!("Replayed {} messages from {}", replayed, recording.display());
+        dispatcher.finish();
+    })
+}
@@ -0,0 +1,387 @@
+use std::{fs, io, io::{Cursor, Read, Write}};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use nbt::CompoundTag;
+
+const SECTOR_SIZE: usize = 4096;
+const REGION_CHUNKS: usize = 32 * 32;
+const COMPRESSION_GZIP: u8 = 1;
+const COMPRESSION_ZLIB: u8 = 2;
+
+pub fn region_path_from(mut path: PathBuf, x: i32, z: i32) -> PathBuf {
+    path.push(format!("r.{}.{}.mca", x, z));
+    path
+}
+
+/// Coordinates of the region file (as named by `region_path_from`) containing a chunk
+pub fn region_of(chunk_x: i32, chunk_z: i32) -> (i32, i32) {
+    (chunk_x.div_euclid(32), chunk_z.div_euclid(32))
+}
+
+#[derive(Debug)]
+pub struct RegionFile {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl RegionFile {
+    pub fn new(path: PathBuf) -> Option<RegionFile> {
+        let s: &str = path.file_stem()?.to_str()?;
+        let mut sp = s.split('.');
+        sp.next()?;
+        let x = sp.next()?.parse().ok()?;
+        let z = sp.next()?.parse().ok()?;
+        Some(RegionFile {
+            x,
+            z,
+        })
+    }
+}
+
+/// Compressed payload of a chunk as stored in a region file
+#[derive(Debug, Clone)]
+pub struct RegionChunk {
+    pub compression: u8,
+    pub data: Vec<u8>,
+}
+
+impl RegionChunk {
+    pub fn encode(tag: &CompoundTag) -> io::Result<RegionChunk> {
+        let mut data = Vec::new();
+        nbt::encode::write_zlib_compound_tag(&mut data, tag.clone())?;
+        Ok(RegionChunk {
+            compression: COMPRESSION_ZLIB,
+            data,
+        })
+    }
+
+    pub fn decode(&self) -> io::Result<CompoundTag> {
+        let mut reader = Cursor::new(&self.data);
+        let tag = match self.compression {
+            COMPRESSION_GZIP => nbt::decode::read_gzip_compound_tag(&mut reader),
+            COMPRESSION_ZLIB => nbt::decode::read_zlib_compound_tag(&mut reader),
+            compression => return Err(invalid_data(format!("Unknown compression {}", compression))),
+        };
+        tag.map_err(invalid_data)
+    }
+}
+
+/// In memory copy of a region file, modified chunks are written back all at once by `save`
+/// instead of seeking into the file for every chunk
+pub struct Region {
+    pub x: i32,
+    pub z: i32,
+    path: PathBuf,
+    existed: bool,
+    chunks: Vec<Option<RegionChunk>>,
+    timestamps: Vec<u32>,
+}
+
+fn chunk_index(chunk_x: i32, chunk_z: i32) -> usize {
+    ((chunk_x & 31) + (chunk_z & 31) * 32) as usize
+}
+
+fn invalid_data<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Timestamp of chunk `chunk_x`:`chunk_z` in the world `folder`, `None` when the chunk is not there.
+/// Only the header of the region file is read
+pub fn chunk_timestamp<P: AsRef<Path>>(folder: P, chunk_x: i32, chunk_z: i32) -> io::Result<Option<u32>> {
+    let (x, z) = region_of(chunk_x, chunk_z);
+    let path = region_path_from(folder.as_ref().to_path_buf(), x, z);
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut header = Vec::with_capacity(SECTOR_SIZE * 2);
+    file.take((SECTOR_SIZE * 2) as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() < SECTOR_SIZE * 2 {
+        return Err(invalid_data(format!("{}: truncated header ({} bytes)", path.display(), header.len())));
+    }
+    let index = chunk_index(chunk_x, chunk_z);
+    let location = (&header[index * 4..]).read_u32::<BigEndian>()?;
+    let timestamp = (&header[SECTOR_SIZE + index * 4..]).read_u32::<BigEndian>()?;
+    Ok(match location {
+        0 => None,
+        _ => Some(timestamp),
+    })
+}
+
+impl Region {
+    /// Load the region file `r.x.z.mca` of `folder`, starting from an empty region if it does not exist yet
+    pub fn open<P: AsRef<Path>>(folder: P, x: i32, z: i32) -> io::Result<Region> {
+        let path = region_path_from(folder.as_ref().to_path_buf(), x, z);
+        let mut region = Region {
+            x,
+            z,
+            path,
+            existed: false,
+            chunks: vec![None; REGION_CHUNKS],
+            timestamps: vec![0; REGION_CHUNKS],
+        };
+        match fs::read(&region.path) {
+            Ok(data) => {
+                region.existed = true;
+                region.parse(&data)?;
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(region)
+    }
+
+    /// Read the chunks of a region file, damaged chunks are errors so `save` never drops them
+    fn parse(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            // Created but never written, minecraft treats it as an empty region
+            return Ok(());
+        }
+        if data.len() < SECTOR_SIZE * 2 {
+            return Err(invalid_data(format!("{}: truncated header ({} bytes)", self.path.display(), data.len())));
+        }
+        let mut header = Cursor::new(&data[..SECTOR_SIZE * 2]);
+        let mut locations = Vec::with_capacity(REGION_CHUNKS);
+        for _ in 0..REGION_CHUNKS {
+            locations.push(header.read_u32::<BigEndian>()?);
+        }
+        for i in 0..REGION_CHUNKS {
+            self.timestamps[i] = header.read_u32::<BigEndian>()?;
+        }
+        for (i, location) in locations.into_iter().enumerate() {
+            if location == 0 {
+                continue;
+            }
+            let offset = (location >> 8) as usize * SECTOR_SIZE;
+            if offset < SECTOR_SIZE * 2 || offset + 5 > data.len() {
+                return Err(invalid_data(format!("{}: chunk {} points outside of the file", self.path.display(), i)));
+            }
+            let mut chunk = Cursor::new(&data[offset..]);
+            let length = chunk.read_u32::<BigEndian>()? as usize;
+            let compression = chunk.read_u8()?;
+            if length == 0 || offset + 4 + length > data.len() {
+                return Err(invalid_data(format!("{}: chunk {} has an invalid length {}", self.path.display(), i, length)));
+            }
+            self.chunks[i] = Some(RegionChunk {
+                compression,
+                data: data[offset + 5..offset + 4 + length].to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the region file was already on disk
+    pub fn exists(&self) -> bool {
+        self.existed
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, chunk_x: i32, chunk_z: i32) -> bool {
+        self.chunks[chunk_index(chunk_x, chunk_z)].is_some()
+    }
+
+    pub fn load_chunk(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<CompoundTag>> {
+        self.chunks[chunk_index(chunk_x, chunk_z)].as_ref().map(RegionChunk::decode).transpose()
+    }
+
+    pub fn set_chunk(&mut self, chunk_x: i32, chunk_z: i32, tag: &CompoundTag) -> io::Result<()> {
+        let chunk = RegionChunk::encode(tag)?;
+        if (chunk.data.len() + 5 + SECTOR_SIZE - 1) / SECTOR_SIZE > 255 {
+            return Err(invalid_data(format!("Chunk {}:{} is too big for a region file", chunk_x, chunk_z)));
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+        self.set_raw_chunk(chunk_x, chunk_z, Some(chunk), timestamp);
+        Ok(())
+    }
+
+    /// Compressed chunk and its timestamp, as stored in the file
+    pub fn raw_chunk(&self, chunk_x: i32, chunk_z: i32) -> (Option<&RegionChunk>, u32) {
+        let index = chunk_index(chunk_x, chunk_z);
+        (self.chunks[index].as_ref(), self.timestamps[index])
+    }
+
+    /// Replace a chunk by an already compressed one, `None` removes the chunk from the region
+    pub fn set_raw_chunk(&mut self, chunk_x: i32, chunk_z: i32, chunk: Option<RegionChunk>, timestamp: u32) {
+        let index = chunk_index(chunk_x, chunk_z);
+        self.timestamps[index] = if chunk.is_some() { timestamp } else { 0 };
+        self.chunks[index] = chunk;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.is_none())
+    }
+
+    /// Build the whole region file in memory and replace the one on disk
+    pub fn save(&self) -> io::Result<()> {
+        let mut locations = Vec::with_capacity(SECTOR_SIZE);
+        let mut sectors = Vec::new();
+        let mut sector = 2;
+        for chunk in self.chunks.iter() {
+            match chunk {
+                Some(chunk) => {
+                    let start = sectors.len();
+                    sectors.write_u32::<BigEndian>(chunk.data.len() as u32 + 1)?;
+                    sectors.write_u8(chunk.compression)?;
+                    sectors.write_all(&chunk.data)?;
+                    let count = (sectors.len() - start + SECTOR_SIZE - 1) / SECTOR_SIZE;
+                    sectors.resize(start + count * SECTOR_SIZE, 0);
+                    locations.write_u32::<BigEndian>(((sector as u32) << 8) | count as u32)?;
+                    sector += count;
+                },
+                None => locations.write_u32::<BigEndian>(0)?,
+            }
+        }
+        for timestamp in self.timestamps.iter() {
+            locations.write_u32::<BigEndian>(*timestamp)?;
+        }
+        let tmp = self.path.with_extension("mca.tmp");
+        let saved = (|| -> io::Result<()> {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(&locations)?;
+            file.write_all(&sectors)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)
+        })();
+        if saved.is_err() {
+            // The region on disk is untouched, don't leave a partial copy next to it
+            let _ = fs::remove_file(&tmp);
+        }
+        saved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(len: usize, seed: u8) -> RegionChunk {
+        RegionChunk {
+            compression: COMPRESSION_ZLIB,
+            data: (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        fs::write(region_path_from(world.to_path_buf(), 0, 0), b"").unwrap();
+        let region = Region::open(world, 0, 0).unwrap();
+        assert!(region.exists());
+        assert!(region.is_empty());
+        assert_eq!(chunk_timestamp(world, 0, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        fs::write(region_path_from(world.to_path_buf(), 0, 0), vec![0; SECTOR_SIZE + 10]).unwrap();
+        assert_eq!(Region::open(world, 0, 0).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(chunk_timestamp(world, 0, 0).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let mut region = Region::open(world, -1, 0).unwrap();
+        assert!(!region.exists());
+        let small = raw(100, 1);
+        // Spans three sectors
+        let big = raw(SECTOR_SIZE * 2 + 100, 2);
+        region.set_raw_chunk(-32, 0, Some(small.clone()), 1589000000);
+        region.set_raw_chunk(-1, 31, Some(big.clone()), 1589000001);
+        region.save().unwrap();
+
+        let region = Region::open(world, -1, 0).unwrap();
+        assert!(region.exists());
+        let (chunk, timestamp) = region.raw_chunk(-32, 0);
+        assert_eq!(chunk.map(|e| &e.data), Some(&small.data));
+        assert_eq!(timestamp, 1589000000);
+        let (chunk, timestamp) = region.raw_chunk(-1, 31);
+        assert_eq!(chunk.map(|e| &e.data), Some(&big.data));
+        assert_eq!(timestamp, 1589000001);
+        assert!(!region.contains(-2, 0));
+        // Read without loading the whole region
+        assert_eq!(chunk_timestamp(world, -1, 31).unwrap(), Some(1589000001));
+        assert_eq!(chunk_timestamp(world, -2, 0).unwrap(), None);
+        // 2 header sectors, 1 for the small chunk and 3 for the big one
+        assert_eq!(fs::metadata(region.path()).unwrap().len(), (SECTOR_SIZE * 6) as u64);
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let mut tag = CompoundTag::new();
+        tag.insert_i32("xPos", -33);
+        let mut region = Region::open(world, -2, -1).unwrap();
+        region.set_chunk(-33, -1, &tag).unwrap();
+        region.save().unwrap();
+        let region = Region::open(world, -2, -1).unwrap();
+        assert_eq!(region.load_chunk(-33, -1).unwrap().map(|e| e.get_i32("xPos").unwrap()), Some(-33));
+        assert!(region.load_chunk(-33, -2).unwrap().is_none());
+    }
+
+    #[test]
+    fn region_of_chunk() {
+        let region = |chunk_x: i32| {
+            let (x, z) = region_of(chunk_x, -chunk_x);
+            region_path_from(PathBuf::new(), x, z)
+        };
+        assert_eq!(region(-1), PathBuf::from("r.-1.0.mca"));
+        assert_eq!(region(-32), PathBuf::from("r.-1.1.mca"));
+        assert_eq!(region(-33), PathBuf::from("r.-2.1.mca"));
+        assert_eq!(region(31), PathBuf::from("r.0.-1.mca"));
+        assert_eq!(region(32), PathBuf::from("r.1.-1.mca"));
+    }
+
+    #[test]
+    fn negative_chunk_index() {
+        assert_eq!(chunk_index(0, 0), 0);
+        assert_eq!(chunk_index(-1, 0), 31);
+        assert_eq!(chunk_index(0, -1), 31 * 32);
+        assert_eq!(chunk_index(-32, -32), 0);
+        assert_eq!(chunk_index(-33, 33), 31 + 32);
+    }
+
+    #[test]
+    fn chunk_too_big() {
+        // Random bytes don't compress, past 255 sectors the location can't hold the sector count
+        let mut seed: u32 = 1;
+        let noise: Vec<i8> = (0..256 * SECTOR_SIZE)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as i8
+            })
+            .collect();
+        let mut tag = CompoundTag::new();
+        tag.insert_i8_vec("noise", noise);
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let mut region = Region::open(world, 0, 0).unwrap();
+        assert_eq!(region.set_chunk(0, 0, &tag).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert!(region.is_empty());
+    }
+
+    #[test]
+    fn failed_save_removes_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let world = dir.path();
+        let mut region = Region::open(world, 0, 0).unwrap();
+        region.set_raw_chunk(0, 0, Some(raw(10, 0)), 0);
+        // The rename can't replace a directory
+        fs::create_dir(region.path()).unwrap();
+        assert!(region.save().is_err());
+        assert!(!region.path().with_extension("mca.tmp").exists());
+    }
+}
@@ -0,0 +1,142 @@
+use actix::prelude::*;
+use std::collections::BTreeMap;
+use nbt::CompoundTag;
+use serde::Serialize;
+
+use crate::region::region_of;
+use crate::find::{for_each_chunk, ChunkSource};
+use crate::models::unpack_block_states;
+
+/// Block counts of a set of chunks
+#[derive(Debug, Default, Serialize)]
+pub struct BlockStats {
+    /// Total number of each block
+    pub blocks: BTreeMap<String, u64>,
+    /// Number of each block per y level
+    pub levels: BTreeMap<String, BTreeMap<i32, u64>>,
+    /// Number of each block per region file
+    pub regions: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl BlockStats {
+    /// Count the blocks of a chunk, `filter` restricts the counted blocks when not empty
+    pub fn add_chunk(&mut self, region: &str, chunk: &CompoundTag, filter: &[String]) -> Option<()> {
+        let level = chunk.get_compound_tag("Level").ok()?;
+        for section in level.get_compound_tag_vec("Sections").ok()? {
+            let _ = self.add_section(region, section, filter);
+        }
+        Some(())
+    }
+
+    fn add_section(&mut self, region: &str, section: &CompoundTag, filter: &[String]) -> Option<()> {
+        let y = section.get_i8("Y").ok()? as i32;
+        let palette: Vec<&str> = section.get_compound_tag_vec("Palette").ok()?
+            .into_iter()
+            .map(|e| e.get_str("Name").unwrap_or("minecraft:air"))
+            .collect();
+        let states = section.get_i64_vec("BlockStates").ok()?;
+        let mut counts = vec![vec![0u64; 16]; palette.len()];
+        for (i, index) in unpack_block_states(&states[..], palette.len()).into_iter().enumerate() {
+            if let Some(count) = counts.get_mut(index) {
+                count[i >> 8] += 1;
+            }
+        }
+        for (name, counts) in palette.into_iter().zip(counts) {
+            if !filter.is_empty() && !filter.iter().any(|f| f == name) {
+                continue;
+            }
+            let total: u64 = counts.iter().sum();
+            if total == 0 {
+                continue;
+            }
+            *self.blocks.entry(name.to_string()).or_insert(0) += total;
+            *self.regions.entry(region.to_string()).or_default().entry(name.to_string()).or_insert(0) += total;
+            let levels = self.levels.entry(name.to_string()).or_default();
+            for (dy, count) in counts.into_iter().enumerate().filter(|(_, count)| *count > 0) {
+                *levels.entry(y * 16 + dy as i32).or_insert(0) += count;
+            }
+        }
+        Some(())
+    }
+
+    pub fn merge(&mut self, other: BlockStats) {
+        for (name, count) in other.blocks {
+            *self.blocks.entry(name).or_insert(0) += count;
+        }
+        for (name, levels) in other.levels {
+            let entry = self.levels.entry(name).or_default();
+            for (y, count) in levels {
+                *entry.entry(y).or_insert(0) += count;
+            }
+        }
+        for (region, blocks) in other.regions {
+            let entry = self.regions.entry(region).or_default();
+            for (name, count) in blocks {
+                *entry.entry(name).or_insert(0) += count;
+            }
+        }
+    }
+
+    pub fn print_table(&self, levels: bool) {
+        let total: u64 = self.blocks.values().sum();
+        let mut blocks: Vec<_> = self.blocks.iter().collect();
+        blocks.sort_by(|a, b| b.1.cmp(a.1));
+        println!("{:<48} {:>14} {:>8}", "BLOCK", "COUNT", "%");
+        for (name, count) in blocks {
+            println!("{:<48} {:>14} {:>7.3}%", name, count, *count as f64 * 100.0 / total.max(1) as f64);
+        }
+        println!();
+        println!("{:<24} {:<48} {:>14}", "REGION", "BLOCK", "COUNT");
+        for (region, blocks) in self.regions.iter() {
+            for (name, count) in blocks {
+                println!("{:<24} {:<48} {:>14}", region, name, count);
+            }
+        }
+        if levels {
+            println!();
+            println!("{:<48} {:>4} {:>14}", "BLOCK", "Y", "COUNT");
+            for (name, levels) in self.levels.iter() {
+                for (y, count) in levels {
+                    println!("{:<48} {:>4} {:>14}", name, y, count);
+                }
+            }
+        }
+    }
+}
+
+pub struct StatsActor {
+    filter: Vec<String>,
+    path: String,
+}
+
+impl StatsActor {
+    pub fn new(filter: Vec<String>, path: String) -> Self {
+        Self {
+            filter,
+            path,
+        }
+    }
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Option<BlockStats>")]
+pub struct StatsRequest(pub ChunkSource);
+
+impl Handler<StatsRequest> for StatsActor {
+    type Result = Option<BlockStats>;
+
+    fn handle(&mut self, msg: StatsRequest, _ctx: &mut SyncContext<Self>) -> Option<BlockStats> {
+        let mut stats = BlockStats::default();
+        let filter = &self.filter[..];
+        for_each_chunk(&self.path, &msg.0, |cx, cz, chunk| {
+            let (rx, rz) = region_of(cx, cz);
+            let name = format!("r.{}.{}", rx, rz);
+            stats.add_chunk(&name, &chunk, filter);
+        });
+        Some(stats)
+    }
+}
+
+impl actix::Actor for StatsActor {
+    type Context = SyncContext<Self>;
+}
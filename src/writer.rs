@@ -0,0 +1,235 @@
+use actix::prelude::*;
+use nbt::CompoundTag;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backup::Backup;
+use crate::conflict::ConflictPolicy;
+use crate::region::{chunk_timestamp, region_of, Region};
+
+/// What happened to a chunk sent to a writer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Written {
+    Added,
+    Overwritten,
+    Kept,
+}
+
+/// Why a chunk could not be written
+#[derive(Debug)]
+pub enum WriteError {
+    /// The region file of the chunk could not be read, nothing was written
+    Load(String),
+    /// The chunk it replaces could not be backed up, nothing was written
+    Backup(String),
+    /// The region file could not be written
+    Save(String),
+    /// The writer is gone, the server is shutting down
+    Stopped,
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WriteError::Load(e) => write!(f, "Failed to load the region: {}", e),
+            WriteError::Backup(e) => write!(f, "Failed to backup: {}", e),
+            WriteError::Save(e) => write!(f, "Failed to save: {}", e),
+            WriteError::Stopped => write!(f, "Writer stopped"),
+        }
+    }
+}
+
+impl Written {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Written::Added => "added",
+            Written::Overwritten => "overwritten",
+            Written::Kept => "kept",
+        }
+    }
+}
+
+pub type WriteResult = Result<Written, WriteError>;
+
+/// Write a decoded chunk to the world, the result is sent back on `reply`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WriteChunk {
+    pub x: i32,
+    pub z: i32,
+    pub chunk: CompoundTag,
+    pub reply: mpsc::Sender<WriteResult>,
+}
+
+/// What the world and the session know about a chunk
+#[derive(Debug)]
+pub struct ChunkStatus {
+    /// Timestamp of the chunk in its region file (seconds since the epoch), `None` when not in the world
+    pub saved: Option<u32>,
+    /// When the chunk was written by this session (milliseconds since the epoch) and with which result
+    pub imported: Option<(u64, Written)>,
+}
+
+/// Look up a chunk, the status is sent back on `reply`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GetChunkStatus {
+    pub x: i32,
+    pub z: i32,
+    pub reply: mpsc::Sender<io::Result<ChunkStatus>>,
+}
+
+/// Owns the region files of its shard, the only place where `listen` writes to the world
+pub struct RegionWriter {
+    folder: String,
+    /// Chunks written since the writer started
+    imported: HashMap<(i32, i32), (u64, Written)>,
+    on_conflict: ConflictPolicy,
+    dry_run: bool,
+    backup: Option<Arc<Backup>>,
+}
+
+impl Actor for RegionWriter {
+    type Context = Context<Self>;
+}
+
+impl RegionWriter {
+    fn write(&self, chunk_x: i32, chunk_z: i32, chunk: CompoundTag) -> WriteResult {
+        let (rx, rz) = region_of(chunk_x, chunk_z);
+        // A damaged region is refused rather than written back without its damaged chunks
+        let mut region = Region::open(&self.folder, rx, rz).map_err(|e| WriteError::Load(e.to_string()))?;
+        let (existing, timestamp) = region.raw_chunk(chunk_x, chunk_z);
+        let existing = existing.cloned();
+        let exists = existing.is_some();
+        let original = match &existing {
+            Some(existing) if self.on_conflict != ConflictPolicy::Overwrite => Some(existing.decode().map_err(|e| WriteError::Load(e.to_string()))?),
+            _ => None,
+        };
+        let written = match exists {
+            true => Written::Overwritten,
+            false => Written::Added,
+        };
+        match self.on_conflict.resolve(original, chunk) {
+            Some(_) if self.dry_run && exists => info!("{}:{} Would overwrite chunk in r.{}.{}.mca", chunk_x, chunk_z, rx, rz),
+            Some(_) if self.dry_run => info!("{}:{} Would add chunk to r.{}.{}.mca", chunk_x, chunk_z, rx, rz),
+            Some(chunk) => {
+                if let Some(backup) = &self.backup {
+                    // Restored along with its timestamp by undo
+                    backup.snapshot(chunk_x, chunk_z, existing.as_ref(), timestamp)
+                        .and_then(|_| backup.flush())
+                        .map_err(|e| WriteError::Backup(e.to_string()))?;
+                }
+                region.set_chunk(chunk_x, chunk_z, &chunk)
+                    .and_then(|_| region.save())
+                    .map_err(|e| WriteError::Save(e.to_string()))?;
+                info!("{}:{} Patched !", chunk_x, chunk_z);
+            },
+            None => {
+                info!("{}:{} Kept existing chunk", chunk_x, chunk_z);
+                return Ok(Written::Kept);
+            },
+        }
+        Ok(written)
+    }
+}
+
+impl Handler<WriteChunk> for RegionWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: WriteChunk, _ctx: &mut Context<Self>) {
+        let result = self.write(msg.x, msg.z, msg.chunk);
+        match &result {
+            Ok(written) if !self.dry_run => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+                self.imported.insert((msg.x, msg.z), (time, *written));
+            },
+            Ok(_) => {},
+            Err(e) => error!("{}:{} {}", msg.x, msg.z, e),
+        }
+        // The sender may have given up on the answer
+        let _ = msg.reply.send(result);
+    }
+}
+
+impl Handler<GetChunkStatus> for RegionWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: GetChunkStatus, _ctx: &mut Context<Self>) {
+        let status = chunk_timestamp(&self.folder, msg.x, msg.z).map(|saved| ChunkStatus {
+            saved,
+            imported: self.imported.get(&(msg.x, msg.z)).cloned(),
+        });
+        let _ = msg.reply.send(status);
+    }
+}
+
+/// One writer per shard, see `shard`
+pub struct RegionWriters {
+    shards: Vec<Addr<RegionWriter>>,
+    arbiters: Vec<Arbiter>,
+}
+
+impl RegionWriters {
+    /// Start `jobs` writers, each on its own arbiter so they keep running while the caller blocks
+    pub fn start(output: &str, jobs: usize, on_conflict: ConflictPolicy, dry_run: bool, backup: Option<Arc<Backup>>) -> Self {
+        let arbiters: Vec<Arbiter> = (0..jobs.max(1)).map(|_| Arbiter::new()).collect();
+        let shards = arbiters.iter()
+            .map(|arbiter| {
+                let output = output.to_string();
+                let backup = backup.clone();
+                RegionWriter::start_in_arbiter(arbiter, move |_| RegionWriter {
+                    folder: output,
+                    imported: HashMap::new(),
+                    on_conflict,
+                    dry_run,
+                    backup,
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            arbiters,
+        }
+    }
+
+    /// Writer owning the region of chunk `chunk_x`:`chunk_z`
+    fn shard(&self, chunk_x: i32, chunk_z: i32) -> &Addr<RegionWriter> {
+        let (x, z) = region_of(chunk_x, chunk_z);
+        &self.shards[(x.wrapping_mul(31).wrapping_add(z) as usize) % self.shards.len()]
+    }
+
+    /// Write chunk `chunk_x`:`chunk_z`, blocking until its writer is done with it
+    pub fn write(&self, chunk_x: i32, chunk_z: i32, chunk: CompoundTag) -> WriteResult {
+        let (reply, result) = mpsc::channel();
+        self.shard(chunk_x, chunk_z).do_send(WriteChunk {
+            x: chunk_x,
+            z: chunk_z,
+            chunk,
+            reply,
+        });
+        // The message is dropped without an answer when the writer is stopped
+        result.recv().unwrap_or(Err(WriteError::Stopped))
+    }
+
+    /// Status of chunk `chunk_x`:`chunk_z`, read by its writer so it never sees a region being written
+    pub fn status(&self, chunk_x: i32, chunk_z: i32) -> io::Result<ChunkStatus> {
+        let (reply, result) = mpsc::channel();
+        self.shard(chunk_x, chunk_z).do_send(GetChunkStatus {
+            x: chunk_x,
+            z: chunk_z,
+            reply,
+        });
+        result.recv().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Writer stopped")))
+    }
+
+    /// Stop the writers once they are done with the chunks already sent
+    pub fn stop(self) {
+        drop(self.shards);
+        for mut arbiter in self.arbiters {
+            arbiter.stop();
+            let _ = arbiter.join();
+        }
+    }
+}